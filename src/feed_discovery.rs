@@ -0,0 +1,106 @@
+/*
+ * Feed auto-discovery
+ *
+ * When the URL given to !addfeed is a web page, we look for the feeds it advertises :
+ * <link rel="alternate" type="application/rss+xml" href="...">
+ */
+
+use regex::Regex;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::html_text::to_plain_text;
+use crate::http_fetch;
+
+const FEED_TYPES: [&str; 3] = [
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/feed+json",
+];
+
+static LINK_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<link\b[^>]*>").expect("Wrong regex!"));
+static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?s)([\w-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).expect("Wrong regex!")
+});
+
+pub struct Discovered {
+    pub url: String,
+    pub title: String,
+    pub items: usize,
+}
+
+// Returns the absolute URLs of the feeds advertised by an HTML page
+fn feed_links(base: &url::Url, html: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    for tag in LINK_TAG.find_iter(html) {
+        let (mut rel, mut link_type, mut href) = (String::new(), String::new(), None);
+        for attr in ATTRIBUTE.captures_iter(tag.as_str()) {
+            let value = attr
+                .get(2)
+                .or_else(|| attr.get(3))
+                .or_else(|| attr.get(4))
+                .map_or("", |v| v.as_str())
+                .replace("&amp;", "&");
+            match attr[1].to_lowercase().as_str() {
+                "rel" => rel = value.to_lowercase(),
+                "type" => link_type = value.to_lowercase(),
+                "href" => href = Some(value),
+                _ => {}
+            }
+        }
+        if !rel.split_whitespace().any(|r| r == "alternate")
+            || !FEED_TYPES.contains(&link_type.trim())
+        {
+            continue;
+        }
+        if let Some(url) = href.and_then(|href| base.join(href.trim()).ok())
+            && !links.contains(&url.to_string())
+        {
+            links.push(url.to_string());
+        }
+    }
+    links
+}
+
+fn get(url: &str, timeout: Duration) -> Result<Vec<u8>, String> {
+    let response = http_fetch::fetch(url, &http_fetch::HttpCache::new(), timeout)
+        .map_err(|e| format!("'{url}' : {}", e.reason))?;
+    response
+        .body
+        .ok_or_else(|| format!("'{url}' : empty response"))
+}
+
+fn parse(url: &str, body: &[u8]) -> Result<Discovered, String> {
+    let feed = feed_rs::parser::parse(body).map_err(|e| format!("'{url}' : {e}"))?;
+    Ok(Discovered {
+        url: url.to_string(),
+        title: feed
+            .title
+            .map_or_else(|| "Unknown".to_string(), |t| to_plain_text(&t.content)),
+        items: feed.entries.len(),
+    })
+}
+
+// Returns the feed at 'url', or the first valid feed advertised by the page at 'url'
+pub fn discover(url: &str, timeout: Duration) -> Result<Discovered, String> {
+    let base = url::Url::parse(url).map_err(|e| format!("'{url}' : {e}"))?;
+    let body = get(url, timeout)?;
+    let parse_error = match parse(url, &body) {
+        Ok(discovered) => return Ok(discovered),
+        Err(e) => e,
+    };
+
+    let links = feed_links(&base, &String::from_utf8_lossy(&body));
+    if links.is_empty() {
+        return Err(format!("{parse_error}, and no feed is advertised there"));
+    }
+    let mut errors = Vec::new();
+    for link in links {
+        match get(&link, timeout).and_then(|body| parse(&link, &body)) {
+            Ok(discovered) => return Ok(discovered),
+            Err(e) => errors.push(e),
+        }
+    }
+    Err(format!("no valid feed found : {}", errors.join(", ")))
+}
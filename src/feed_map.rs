@@ -0,0 +1,97 @@
+/*
+ * A value per feed URL, shared between threads and saved to disk as JSON
+ *
+ * The HTTP validators (http_fetch) and the status of the feeds (feed_status) are kept this way.
+ * A missing file is an empty map, an unreadable one is reported and ignored.
+ */
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::gruik_config::write_atomically;
+
+pub struct FeedMap<T> {
+    inner: Arc<Mutex<HashMap<String, T>>>,
+}
+
+impl<T> Clone for FeedMap<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Default for FeedMap<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T: Clone + Default + Serialize + DeserializeOwned> FeedMap<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load_file(&self, filename: &str) {
+        let buf = match fs::read_to_string(filename) {
+            Ok(r) => r,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                println!("Can't read {filename} : {e}");
+                return;
+            }
+        };
+        match serde_json::from_str(&buf) {
+            Ok(r) => *self.inner.lock().expect("Poisoned lock!") = r,
+            Err(e) => println!("Can't parse {filename} : {e}"),
+        }
+    }
+
+    pub fn save_file(&self, filename: &str) {
+        let json =
+            serde_json::to_string(&*self.inner.lock().expect("Poisoned lock!")).unwrap_or_default();
+        if let Err(e) = write_atomically(filename, &json) {
+            println!("Failed to write {filename} : {e}");
+        }
+    }
+
+    pub fn get(&self, url: &str) -> T {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .get(url)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, url: &str, value: T) {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .insert(url.to_string(), value);
+    }
+
+    // Changes the value of a feed (the default value if it has none yet)
+    pub fn update<R>(&self, url: &str, f: impl FnOnce(&mut T) -> R) -> R {
+        f(self
+            .inner
+            .lock()
+            .expect("Poisoned lock!")
+            .entry(url.to_string())
+            .or_default())
+    }
+
+    // Forgets the feeds that are not in 'urls' anymore
+    pub fn retain(&self, urls: &[&str]) {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .retain(|url, _| urls.contains(&url.as_str()));
+    }
+}
@@ -0,0 +1,61 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::feed_map::FeedMap;
+
+// A failing feed is never fetched less often than this
+const MAX_BACKOFF: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct FeedStatus {
+    // Consecutive failures
+    pub failures: u32,
+    pub last_error: Option<String>,
+    pub last_success: Option<DateTime<Utc>>,
+    // Disabled after too many failures
+    pub disabled: bool,
+}
+
+impl FeedStatus {
+    // The frequency doubles after every failure
+    pub fn backoff(&self, frequency: Duration) -> Duration {
+        frequency
+            .saturating_mul(2u32.saturating_pow(self.failures.min(31)))
+            .min(MAX_BACKOFF.max(frequency))
+    }
+}
+
+// The status of every feed, shared between the fetcher and the IRC commands
+pub type FeedsStatus = FeedMap<FeedStatus>;
+
+impl FeedsStatus {
+    pub fn is_disabled(&self, url: &str) -> bool {
+        self.get(url).disabled
+    }
+
+    pub fn success(&self, url: &str) {
+        self.update(url, |status| {
+            status.failures = 0;
+            status.last_success = Some(Utc::now());
+        });
+    }
+
+    // Returns the new status of the feed. It gets disabled after 'max_failures' failures (0
+    // means never)
+    pub fn failure(&self, url: &str, error: String, max_failures: u32) -> FeedStatus {
+        self.update(url, |status| {
+            status.failures += 1;
+            status.last_error = Some(error);
+            status.disabled = max_failures > 0 && status.failures >= max_failures;
+            status.clone()
+        })
+    }
+
+    pub fn enable(&self, url: &str) {
+        self.update(url, |status| {
+            status.failures = 0;
+            status.disabled = false;
+        });
+    }
+}
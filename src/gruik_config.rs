@@ -5,12 +5,16 @@ use std::str::FromStr;
 use std::time::Duration;
 use std::{collections::HashMap, fs, sync::Arc, sync::Mutex};
 
+use crate::news_filter::{FilterRule, Filters};
+use crate::template::Template;
+use crate::yaml_edit::{self, SeqEdit};
+
 /*
  * Color codes from :
  * https://modern.ircdocs.horse/formatting#colors
  * https://github.com/lrstanley/girc/blob/master/format.go#L27
  */
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[allow(dead_code)]
 pub enum IrcColor {
     Bold,        // 0x02
@@ -89,14 +93,10 @@ impl fmt::Display for IrcColor {
 }
 
 #[rustfmt::skip]
-impl<'de> Deserialize<'de> for IrcColor {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        use serde::de::Error;
+impl FromStr for IrcColor {
+    type Err = String;
 
-        let s = String::deserialize(deserializer)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
             "bold"        => Ok(Self::Bold),
             "italic"      => Ok(Self::Italic),
@@ -129,11 +129,24 @@ impl<'de> Deserialize<'de> for IrcColor {
             "gray"        => Ok(Self::Gray),
             "lightgrey"   => Ok(Self::LightGrey),
             "silver"      => Ok(Self::Silver),
-            other   => Err(format!("Unknown color '{other}'")).map_err(D::Error::custom),
+            other   => Err(format!("Unknown color '{other}'")),
         }
     }
 }
 
+impl<'de> Deserialize<'de> for IrcColor {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
 #[rustfmt::skip]
 impl Serialize for IrcColor {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -179,18 +192,118 @@ impl Serialize for IrcColor {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SaslMechanism {
+    Plain,
+    External,
+}
+
+impl fmt::Display for SaslMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Plain => write!(f, "PLAIN"),
+            Self::External => write!(f, "EXTERNAL"),
+        }
+    }
+}
+
+// The NickServ command used to get our nick back
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NickServCommand {
+    #[default]
+    Regain,
+    Ghost,
+}
+
+impl fmt::Display for NickServCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Regain => write!(f, "REGAIN"),
+            Self::Ghost => write!(f, "GHOST"),
+        }
+    }
+}
+
+// Where the excerpt of a news (its summary, or the beginning of its content) is posted
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Excerpt {
+    #[default]
+    Off,
+    // At the end of the message
+    Inline,
+    // On its own line, after the message
+    Line,
+}
+
+impl fmt::Display for Excerpt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Off => write!(f, "off"),
+            Self::Inline => write!(f, "inline"),
+            Self::Line => write!(f, "line"),
+        }
+    }
+}
+
+// What to do with the news already posted from another feed (same link, or nearly the same title)
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Duplicates {
+    // They are posted like any other news
+    #[default]
+    Off,
+    // They are not posted
+    Suppress,
+    // They are not posted, their origin is added to the news already posted ("[A, B] title" in
+    // !latest, !search and the HTTP feeds)
+    Merge,
+}
+
+impl fmt::Display for Duplicates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Off => write!(f, "off"),
+            Self::Suppress => write!(f, "suppress"),
+            Self::Merge => write!(f, "merge"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields, default)]
 struct IrcConfig {
     server: String,
     nick: String,
+    alt_nicks: Vec<String>,
+    regain_delay: DurationString,
+    nickserv: NickServCommand,
     channel: String,
     xchannels: Vec<String>,
     password: Option<String>,
+    account: Option<String>,
+    sasl: Option<SaslMechanism>,
     debug: bool,
-    port: u16,
+    port: Option<u16>,
+    tls: bool,
+    tls_verify: bool,
+    tls_ca_file: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    // Flood control : 'burst' lines can be sent at once, then one every 'delay'
     delay: DurationString,
+    burst: u32,
+    // Lines of command replies that can wait to be sent, the next ones are dropped
+    max_reply_lines: usize,
+    // On shutdown, how long the waiting lines have to be sent, and what we say when leaving
+    shutdown_timeout: DurationString,
+    quit_message: String,
     colors: HashMap<String, IrcColor>,
+    // Format of the news, and its overrides for some channels
+    template: Template,
+    templates: HashMap<String, Template>,
     ops: Vec<String>,
 }
 
@@ -199,31 +312,339 @@ impl Default for IrcConfig {
         Self {
             server: "irc.libera.chat".to_string(),
             nick: "gruik".to_string(),
+            alt_nicks: vec![],
+            regain_delay: DurationString::from_str("1m").expect("Wrong default!"),
+            nickserv: NickServCommand::default(),
             channel: "#goaste".to_string(),
             xchannels: vec!["#goaste2".to_string()],
             password: None,
+            account: None,
+            sasl: None,
             debug: false,
-            port: 6667,
+            port: None,
+            tls: false,
+            tls_verify: true,
+            tls_ca_file: None,
+            tls_cert: None,
+            tls_key: None,
             delay: DurationString::from_str("2s").expect("Wrong default!"),
+            burst: 4,
+            max_reply_lines: 20,
+            shutdown_timeout: DurationString::from_str("10s").expect("Wrong default!"),
+            quit_message: "Bye".to_string(),
             colors: HashMap::from([
                 ("origin".to_string(), IrcColor::Pink),
                 ("title".to_string(), IrcColor::Bold),
                 ("hash".to_string(), IrcColor::LightGrey),
                 ("link".to_string(), IrcColor::LightBlue),
+                ("excerpt".to_string(), IrcColor::Grey),
             ]),
+            template: Template::default(),
+            templates: HashMap::new(),
             ops: vec![],
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl IrcConfig {
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(if self.tls { 6697 } else { 6667 })
+    }
+    // Any difference here means that we have to connect (and register) again
+    fn needs_reconnection(&self, new: &Self) -> bool {
+        self.server != new.server
+            || self.port() != new.port()
+            || self.tls != new.tls
+            || self.tls_verify != new.tls_verify
+            || self.tls_ca_file != new.tls_ca_file
+            || self.tls_cert != new.tls_cert
+            || self.tls_key != new.tls_key
+            || self.sasl != new.sasl
+            || (new.sasl.is_some()
+                && (self.account != new.account || self.password != new.password))
+    }
+    fn check_sasl(&self) -> Result<(), String> {
+        match self.sasl {
+            Some(SaslMechanism::Plain) if self.password.is_none() => {
+                Err("SASL PLAIN needs irc.password".to_string())
+            }
+            Some(SaslMechanism::External) if !self.tls => {
+                Err("SASL EXTERNAL needs irc.tls".to_string())
+            }
+            Some(SaslMechanism::External) if self.tls_cert.is_none() || self.tls_key.is_none() => {
+                Err("SASL EXTERNAL needs irc.tls_cert and irc.tls_key".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// A list of channels can also be written '#a,#b' (like in a JOIN command)
+fn channel_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Channels {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Channels::deserialize(deserializer)? {
+        Channels::One(channels) => channels
+            .split(',')
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .collect(),
+        Channels::Many(channels) => channels,
+    })
+}
+
+// A feed, with the settings overriding the global ones
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct FeedConfig {
+    url: String,
+    // Replaces the feed title as the origin of the news
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency: Option<DurationString>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maxage: Option<DurationString>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maxnews: Option<u16>,
+    // Channels the news are posted to, irc.channel if empty
+    #[serde(default, alias = "channel", deserialize_with = "channel_list")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    channels: Vec<String>,
+    #[serde(default = "FeedConfig::enabled_default")]
+    #[serde(skip_serializing_if = "FeedConfig::is_enabled")]
+    enabled: bool,
+    // Color of the origin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<IrcColor>,
+    // Added to the global rules
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<FilterRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude: Vec<FilterRule>,
+    // Replaces the channel and global templates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    template: Option<Template>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    excerpt: Option<Excerpt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    excerpt_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dedup_title: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicates: Option<Duplicates>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicates_window: Option<DurationString>,
+}
+
+impl FeedConfig {
+    const fn enabled_default() -> bool {
+        true
+    }
+    // Only 'enabled: false' is written
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    const fn is_enabled(enabled: &bool) -> bool {
+        *enabled
+    }
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            name: None,
+            frequency: None,
+            maxage: None,
+            maxnews: None,
+            channels: vec![],
+            enabled: true,
+            color: None,
+            include: vec![],
+            exclude: vec![],
+            template: None,
+            excerpt: None,
+            excerpt_length: None,
+            dedup_title: None,
+            duplicates: None,
+            duplicates_window: None,
+        }
+    }
+    // Builds a feed from 'key=value' options (from an IRC command for example)
+    fn from_options(url: String, options: &[&str]) -> Result<Self, String> {
+        let mut mapping = serde_yaml::Mapping::new();
+        mapping.insert("url".into(), url.into());
+        for option in options {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("'{option}' : options must be written as key=value"))?;
+            // Channel names would be read as YAML comments
+            let value = match serde_yaml::from_str(value) {
+                Ok(serde_yaml::Value::Null) | Err(_) => value.into(),
+                Ok(v) => v,
+            };
+            mapping.insert(key.into(), value);
+        }
+        Self::deserialize(serde_yaml::Value::Mapping(mapping)).map_err(|e| e.to_string())
+    }
+}
+
+// In the config file, a feed is either an URL or a FeedConfig
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+enum FeedEntry {
+    Url(String),
+    Detailed(Box<FeedConfig>),
+}
+
+impl<'de> Deserialize<'de> for FeedEntry {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        // Not using #[serde(untagged)] here gives meaningful errors for the detailed form
+        match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::String(url) => Ok(Self::Url(url)),
+            value => FeedConfig::deserialize(value)
+                .map(|feed| Self::Detailed(Box::new(feed)))
+                .map_err(D::Error::custom),
+        }
+    }
+}
+
+impl FeedEntry {
+    fn url(&self) -> &str {
+        match self {
+            Self::Url(url) => url,
+            Self::Detailed(feed) => &feed.url,
+        }
+    }
+    fn config(&self) -> FeedConfig {
+        match self {
+            Self::Url(url) => FeedConfig::new(url.clone()),
+            Self::Detailed(feed) => (**feed).clone(),
+        }
+    }
+}
+
+impl fmt::Display for FeedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let feed = match self {
+            Self::Url(url) => return write!(f, "{url}"),
+            Self::Detailed(feed) => feed,
+        };
+        let mut details = Vec::new();
+        if let Some(name) = &feed.name {
+            details.push(format!("name: {name}"));
+        }
+        if !feed.channels.is_empty() {
+            details.push(format!("channels: {}", feed.channels.join(",")));
+        }
+        if let Some(frequency) = &feed.frequency {
+            details.push(format!("frequency: {frequency}"));
+        }
+        if let Some(maxage) = &feed.maxage {
+            details.push(format!("maxage: {maxage}"));
+        }
+        if let Some(maxnews) = &feed.maxnews {
+            details.push(format!("maxnews: {maxnews}"));
+        }
+        if let Some(color) = &feed.color {
+            details.push(format!(
+                "color: {}",
+                serde_yaml::to_string(color).unwrap_or_default().trim()
+            ));
+        }
+        if let Some(excerpt) = &feed.excerpt {
+            details.push(format!("excerpt: {excerpt}"));
+        }
+        if let Some(excerpt_length) = &feed.excerpt_length {
+            details.push(format!("excerpt_length: {excerpt_length}"));
+        }
+        if let Some(dedup_title) = &feed.dedup_title {
+            details.push(format!("dedup_title: {dedup_title}"));
+        }
+        if let Some(duplicates) = &feed.duplicates {
+            details.push(format!("duplicates: {duplicates}"));
+        }
+        if let Some(duplicates_window) = &feed.duplicates_window {
+            details.push(format!("duplicates_window: {duplicates_window}"));
+        }
+        if !feed.include.is_empty() || !feed.exclude.is_empty() {
+            details.push(format!(
+                "filters: {} include, {} exclude",
+                feed.include.len(),
+                feed.exclude.len()
+            ));
+        }
+        if !feed.enabled {
+            details.push("disabled".to_string());
+        }
+        if details.is_empty() {
+            write!(f, "{}", feed.url)
+        } else {
+            write!(f, "{} ({})", feed.url, details.join(", "))
+        }
+    }
+}
+
+// A feed coming from another tool (an OPML file for example)
+pub struct NewFeed {
+    pub url: String,
+    pub name: Option<String>,
+    pub channels: Vec<String>,
+    pub enabled: bool,
+}
+
+// A feed, with the global settings applied
+#[derive(Debug, Clone)]
+pub struct Feed {
+    pub url: String,
+    pub name: Option<String>,
+    pub frequency: Duration,
+    pub maxage: chrono::Duration,
+    pub maxnews: u16,
+    pub channels: Vec<String>,
+    pub enabled: bool,
+    pub color: Option<IrcColor>,
+    pub filters: Filters,
+    pub excerpt: Excerpt,
+    // In bytes
+    pub excerpt_length: usize,
+    // Two news with the same title are the same news
+    pub dedup_title: bool,
+    pub duplicates: Duplicates,
+    // Only the news published that close to each other are duplicates
+    pub duplicates_window: chrono::Duration,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields, default)]
 struct FeedsConfig {
-    urls: Vec<String>,
+    urls: Vec<FeedEntry>,
     maxnews: u16,
     maxage: DurationString,
     frequency: DurationString,
     ringsize: usize,
+    // Number of feeds fetched at the same time
+    concurrency: usize,
+    // Timeout of a single HTTP request
+    timeout: DurationString,
+    // A feed is disabled after this number of consecutive failures (0 : never)
+    max_failures: u32,
+    // Filters applied to every feed
+    include: Vec<FilterRule>,
+    exclude: Vec<FilterRule>,
+    excerpt: Excerpt,
+    excerpt_length: usize,
+    dedup_title: bool,
+    duplicates: Duplicates,
+    duplicates_window: DurationString,
 }
 
 impl Default for FeedsConfig {
@@ -234,15 +655,347 @@ impl Default for FeedsConfig {
             maxage: DurationString::from_str("1h").expect("Wrong default!"),
             frequency: DurationString::from_str("30m").expect("Wrong default!"),
             ringsize: 100,
+            concurrency: 4,
+            timeout: DurationString::from_str("30s").expect("Wrong default!"),
+            max_failures: 10,
+            include: vec![],
+            exclude: vec![],
+            excerpt: Excerpt::default(),
+            excerpt_length: 200,
+            dedup_title: false,
+            duplicates: Duplicates::default(),
+            duplicates_window: DurationString::from_str("24h").expect("Wrong default!"),
+        }
+    }
+}
+
+// The embedded HTTP server, serving the news as JSON Feed, RSS and Atom
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct HttpConfig {
+    // ex: 127.0.0.1:8080
+    bind: String,
+    #[serde(default = "HttpConfig::title_default")]
+    title: String,
+}
+
+impl HttpConfig {
+    fn title_default() -> String {
+        "gruik".to_string()
+    }
+}
+
+// Where the posted news are kept
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    // The latest feeds.ringsize news, in {irc.channel}-feed.json
+    #[default]
+    Json,
+    // Every news, until they are too old or too many
+    Sqlite,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+struct StoreConfig {
+    backend: StoreBackend,
+    // The SQLite database, {irc.channel}-feed.db by default
+    path: Option<String>,
+    // Retention of the SQLite store (0 : no limit)
+    max_age: DurationString,
+    max_news: usize,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            backend: StoreBackend::default(),
+            path: None,
+            max_age: DurationString::from_str("365d").expect("Wrong default!"),
+            max_news: 100_000,
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 struct GruikConfigYaml {
     irc: IrcConfig,
     feeds: FeedsConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http: Option<HttpConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    store: Option<StoreConfig>,
+}
+
+fn file_hash(content: &str) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(content).to_vec()
+}
+
+// Writes a temporary file first, so that the config file is never left half-written
+pub fn write_atomically(filename: &str, content: &str) -> Result<(), String> {
+    use std::io::Write;
+    use std::path::Path;
+
+    let path = Path::new(filename);
+    let tmp_path = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name()
+            .map_or_else(Default::default, |f| f.to_string_lossy())
+    ));
+    let mut f = fs::File::create(&tmp_path)
+        .map_err(|e| format!("Can't create '{}' : {e}", tmp_path.display()))?;
+    f.write_all(content.as_bytes())
+        .and_then(|()| f.sync_all())
+        .map_err(|e| format!("Can't write '{}' : {e}", tmp_path.display()))?;
+    if let Ok(metadata) = fs::metadata(path) {
+        let _ = fs::set_permissions(&tmp_path, metadata.permissions());
+    }
+    fs::rename(&tmp_path, path).map_err(|e| format!("Can't replace '{filename}' : {e}"))
+}
+
+fn check_feed(feed: &FeedConfig) -> Result<(), String> {
+    let url = &feed.url;
+    match url::Url::parse(url) {
+        Ok(u) if ["http", "https"].contains(&u.scheme()) => {}
+        Ok(u) => return Err(format!("'{url}' : unsupported scheme '{}'", u.scheme())),
+        Err(e) => return Err(format!("'{url}' : {e}")),
+    }
+    for channel in &feed.channels {
+        if !channel.starts_with(['#', '&']) || channel.len() < 2 {
+            return Err(format!("'{url}' : '{channel}' is not a valid channel name"));
+        }
+    }
+    if feed.frequency.is_some_and(|d| Duration::from(d).is_zero()) {
+        return Err(format!("'{url}' : frequency can't be 0"));
+    }
+    if feed
+        .include
+        .iter()
+        .chain(&feed.exclude)
+        .any(FilterRule::is_empty)
+    {
+        return Err(format!("'{url}' : a filter rule needs at least one field"));
+    }
+    Ok(())
+}
+
+impl GruikConfigYaml {
+    // Every channel we have to join
+    fn channels(&self) -> Vec<String> {
+        let mut channels = vec![self.irc.channel.clone()];
+        for channel in self
+            .irc
+            .xchannels
+            .iter()
+            .chain(self.feeds.urls.iter().flat_map(|f| match f {
+                FeedEntry::Detailed(feed) => feed.channels.iter(),
+                FeedEntry::Url(_) => [].iter(),
+            }))
+        {
+            if !channels.contains(channel) {
+                channels.push(channel.clone());
+            }
+        }
+        channels
+    }
+    fn feed(&self, entry: &FeedEntry) -> Feed {
+        let feed = entry.config();
+        let maxage: Duration = feed.maxage.unwrap_or(self.feeds.maxage).into();
+        let duplicates_window: Duration = feed
+            .duplicates_window
+            .unwrap_or(self.feeds.duplicates_window)
+            .into();
+        Feed {
+            url: feed.url,
+            name: feed.name,
+            frequency: feed.frequency.unwrap_or(self.feeds.frequency).into(),
+            maxage: chrono::Duration::from_std(maxage).expect("Wrong conversion!"),
+            maxnews: feed.maxnews.unwrap_or(self.feeds.maxnews),
+            channels: if feed.channels.is_empty() {
+                vec![self.irc.channel.clone()]
+            } else {
+                feed.channels
+            },
+            enabled: feed.enabled,
+            color: feed.color,
+            filters: Filters {
+                include: [self.feeds.include.clone(), feed.include].concat(),
+                exclude: [self.feeds.exclude.clone(), feed.exclude].concat(),
+            },
+            excerpt: feed.excerpt.unwrap_or(self.feeds.excerpt),
+            excerpt_length: feed.excerpt_length.unwrap_or(self.feeds.excerpt_length),
+            dedup_title: feed.dedup_title.unwrap_or(self.feeds.dedup_title),
+            duplicates: feed.duplicates.unwrap_or(self.feeds.duplicates),
+            duplicates_window: chrono::Duration::from_std(duplicates_window)
+                .expect("Wrong conversion!"),
+        }
+    }
+    fn read(filename: &str) -> Result<(Self, Vec<u8>), String> {
+        let yaml =
+            fs::read_to_string(filename).map_err(|e| format!("Can't read '{filename}' : {e}"))?;
+        let gruik_config_yaml: Self =
+            serde_yaml::from_str(&yaml).map_err(|e| format!("Can't parse '{filename}' : {e}"))?;
+        gruik_config_yaml
+            .validate()
+            .map_err(|e| format!("Wrong configuration in '{filename}' : {e}"))?;
+        Ok((gruik_config_yaml, file_hash(&yaml)))
+    }
+
+    // Everything serde can't check by itself
+    fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        if self.irc.server.is_empty() {
+            errors.push("irc.server is empty".to_string());
+        }
+        if self.irc.nick.is_empty() {
+            errors.push("irc.nick is empty".to_string());
+        }
+        if let Some(http) = &self.http
+            && http.bind.parse::<std::net::SocketAddr>().is_err()
+        {
+            errors.push(format!(
+                "http.bind : '{}' is not an address:port",
+                http.bind
+            ));
+        }
+        if let Some(StoreConfig {
+            path: Some(path), ..
+        }) = &self.store
+            && path.is_empty()
+        {
+            errors.push("store.path can't be empty".to_string());
+        }
+        for channel in self.channels() {
+            if !channel.starts_with(['#', '&']) || channel.len() < 2 {
+                errors.push(format!("'{channel}' is not a valid channel name"));
+            }
+        }
+        for key in self.irc.colors.keys() {
+            if !["origin", "title", "hash", "link", "excerpt"].contains(&key.as_str()) {
+                errors.push(format!("irc.colors : unknown key '{key}'"));
+            }
+        }
+        for channel in self.irc.templates.keys() {
+            if !channel.starts_with(['#', '&']) || channel.len() < 2 {
+                errors.push(format!(
+                    "irc.templates : '{channel}' is not a valid channel name"
+                ));
+            }
+        }
+        if Duration::from(self.irc.delay).is_zero() {
+            errors.push("irc.delay can't be 0".to_string());
+        }
+        if self.irc.burst == 0 {
+            errors.push("irc.burst can't be 0".to_string());
+        }
+        if self.irc.max_reply_lines == 0 {
+            errors.push("irc.max_reply_lines can't be 0".to_string());
+        }
+        if Duration::from(self.feeds.frequency).is_zero() {
+            errors.push("feeds.frequency can't be 0".to_string());
+        }
+        if self.feeds.concurrency == 0 {
+            errors.push("feeds.concurrency can't be 0".to_string());
+        }
+        if self
+            .feeds
+            .include
+            .iter()
+            .chain(&self.feeds.exclude)
+            .any(FilterRule::is_empty)
+        {
+            errors.push("feeds : a filter rule needs at least one field".to_string());
+        }
+        if Duration::from(self.feeds.timeout).is_zero() {
+            errors.push("feeds.timeout can't be 0".to_string());
+        }
+        for entry in &self.feeds.urls {
+            if let Err(e) = check_feed(&entry.config()) {
+                errors.push(e);
+            }
+        }
+        if let Err(e) = self.irc.check_sasl() {
+            errors.push(e);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+}
+
+// What changed in the configuration file since it was last (re)loaded
+#[derive(Debug, Default)]
+pub struct ConfigChanges {
+    pub join: Vec<String>,
+    pub part: Vec<String>,
+    pub nick: Option<String>,
+    pub reconnect: Option<String>,
+    pub feeds_added: usize,
+    pub feeds_removed: usize,
+}
+
+impl ConfigChanges {
+    fn new(old: &GruikConfigYaml, new: &GruikConfigYaml) -> Self {
+        let old_channels = old.channels();
+        let new_channels = new.channels();
+        let old_urls: Vec<&str> = old.feeds.urls.iter().map(FeedEntry::url).collect();
+        let new_urls: Vec<&str> = new.feeds.urls.iter().map(FeedEntry::url).collect();
+        Self {
+            join: new_channels
+                .iter()
+                .filter(|c| !old_channels.contains(c))
+                .cloned()
+                .collect(),
+            part: old_channels
+                .iter()
+                .filter(|c| !new_channels.contains(c))
+                .cloned()
+                .collect(),
+            nick: (old.irc.nick != new.irc.nick).then(|| new.irc.nick.clone()),
+            reconnect: old
+                .irc
+                .needs_reconnection(&new.irc)
+                .then(|| format!("{}:{}", new.irc.server, new.irc.port())),
+            feeds_added: new_urls.iter().filter(|u| !old_urls.contains(u)).count(),
+            feeds_removed: old_urls.iter().filter(|u| !new_urls.contains(u)).count(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigChanges {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut summary = Vec::new();
+        if let Some(server) = &self.reconnect {
+            summary.push(format!("reconnecting to {server}"));
+        }
+        if let Some(nick) = &self.nick {
+            summary.push(format!("nick is now {nick}"));
+        }
+        if !self.join.is_empty() {
+            summary.push(format!("joining {}", self.join.join(", ")));
+        }
+        if !self.part.is_empty() {
+            summary.push(format!("leaving {}", self.part.join(", ")));
+        }
+        if self.feeds_added > 0 || self.feeds_removed > 0 {
+            summary.push(format!(
+                "{} feed(s) added, {} feed(s) removed",
+                self.feeds_added, self.feeds_removed
+            ));
+        }
+        if summary.is_empty() {
+            write!(f, "no change for the IRC connection or the feeds list")
+        } else {
+            write!(f, "{}", summary.join(", "))
+        }
+    }
 }
 
 // The following structure allows sharing the config between multiple threads (or coroutines)
@@ -250,6 +1003,8 @@ struct GruikConfigYaml {
 // get/set values
 pub struct GruikConfig {
     inner: Arc<Mutex<GruikConfigYaml>>,
+    // Hash of the config file, as we last read or wrote it
+    file_hash: Arc<Mutex<Vec<u8>>>,
     pub filename: String,
 }
 
@@ -257,6 +1012,7 @@ impl Clone for GruikConfig {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            file_hash: self.file_hash.clone(),
             filename: self.filename.clone(),
         }
     }
@@ -264,44 +1020,32 @@ impl Clone for GruikConfig {
 
 impl GruikConfig {
     pub fn new(filename: String) -> Self {
-        let yaml = match fs::read_to_string(&filename) {
+        let (gruik_config_yaml, file_hash) = match GruikConfigYaml::read(&filename) {
             Ok(r) => r,
             Err(e) => {
-                println!("Can't read '{}' : {e}\nexiting.", &filename);
-                std::process::exit(1);
-            }
-        };
-
-        let gruik_config_yaml: GruikConfigYaml = match serde_yaml::from_str(&yaml) {
-            Ok(r) => r,
-            Err(e) => {
-                println!("Can't parse '{}' : {e}\nexiting.", &filename);
+                println!("{e}\nexiting.");
                 std::process::exit(1);
             }
         };
         Self {
             inner: Arc::new(Mutex::new(gruik_config_yaml)),
+            file_hash: Arc::new(Mutex::new(file_hash)),
             filename,
         }
     }
-    pub fn reload(&self) {
-        // TODO : Code is duplicated from the new() function above
-        let yaml = match fs::read_to_string(&self.filename) {
-            Ok(r) => r,
-            Err(e) => {
-                println!("Can't read '{}' : {e}\nexiting.", &self.filename);
-                std::process::exit(1);
-            }
-        };
-
-        let gruik_config_yaml: GruikConfigYaml = match serde_yaml::from_str(&yaml) {
-            Ok(r) => r,
-            Err(e) => {
-                println!("Can't parse '{}' : {e}\nexiting.", &self.filename);
-                std::process::exit(1);
-            }
-        };
-        *self.inner.lock().expect("Poisoned lock!") = gruik_config_yaml;
+    // On error, the current configuration is kept.
+    // None is returned if the file is the one we already know (we wrote it for example)
+    pub fn reload(&self) -> Result<Option<ConfigChanges>, String> {
+        let (gruik_config_yaml, file_hash) = GruikConfigYaml::read(&self.filename)?;
+        let mut known_hash = self.file_hash.lock().expect("Poisoned lock!");
+        if *known_hash == file_hash {
+            return Ok(None);
+        }
+        *known_hash = file_hash;
+        let mut gruik_config_guarded = self.inner.lock().expect("Poisoned lock!");
+        let changes = ConfigChanges::new(&gruik_config_guarded, &gruik_config_yaml);
+        *gruik_config_guarded = gruik_config_yaml;
+        Ok(Some(changes))
     }
     pub fn irc_server(&self) -> String {
         self.inner
@@ -312,11 +1056,76 @@ impl GruikConfig {
             .clone()
     }
     pub fn irc_port(&self) -> u16 {
-        self.inner.lock().expect("Poisoned lock!").irc.port
+        self.inner.lock().expect("Poisoned lock!").irc.port()
+    }
+    pub fn irc_tls(&self) -> bool {
+        self.inner.lock().expect("Poisoned lock!").irc.tls
+    }
+    pub fn irc_tls_verify(&self) -> bool {
+        self.inner.lock().expect("Poisoned lock!").irc.tls_verify
+    }
+    pub fn irc_tls_ca_file(&self) -> Option<String> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .irc
+            .tls_ca_file
+            .clone()
+    }
+    pub fn irc_tls_cert(&self) -> Option<String> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .irc
+            .tls_cert
+            .clone()
+    }
+    pub fn irc_tls_key(&self) -> Option<String> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .irc
+            .tls_key
+            .clone()
+    }
+    pub fn irc_sasl(&self) -> Option<SaslMechanism> {
+        self.inner.lock().expect("Poisoned lock!").irc.sasl
+    }
+    // The SASL account defaults to the nick
+    pub fn irc_account(&self) -> String {
+        let irc = &self.inner.lock().expect("Poisoned lock!").irc;
+        irc.account.as_ref().unwrap_or(&irc.nick).clone()
+    }
+    pub fn irc_password(&self) -> Option<String> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .irc
+            .password
+            .clone()
     }
     pub fn irc_nick(&self) -> String {
         self.inner.lock().expect("Poisoned lock!").irc.nick.clone()
     }
+    pub fn irc_alt_nicks(&self) -> Vec<String> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .irc
+            .alt_nicks
+            .clone()
+    }
+    pub fn irc_regain_delay(&self) -> Duration {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .irc
+            .regain_delay
+            .into()
+    }
+    pub fn irc_nickserv(&self) -> NickServCommand {
+        self.inner.lock().expect("Poisoned lock!").irc.nickserv
+    }
     pub fn irc_channel(&self) -> String {
         self.inner
             .lock()
@@ -332,21 +1141,66 @@ impl GruikConfig {
         }
         vec
     }
-    pub fn feeds_urls(&self) -> Vec<String> {
-        let mut vec = Vec::new();
-        for channel in &self.inner.lock().expect("Poisoned lock!").feeds.urls {
-            vec.push(channel.clone());
-        }
-        vec
+    pub fn channels(&self) -> Vec<String> {
+        self.inner.lock().expect("Poisoned lock!").channels()
+    }
+    pub fn feeds(&self) -> Vec<Feed> {
+        let gruik_config_guarded = self.inner.lock().expect("Poisoned lock!");
+        gruik_config_guarded
+            .feeds
+            .urls
+            .iter()
+            .map(|entry| gruik_config_guarded.feed(entry))
+            .collect()
+    }
+    pub fn feed(&self, url: &str) -> Option<Feed> {
+        let gruik_config_guarded = self.inner.lock().expect("Poisoned lock!");
+        gruik_config_guarded
+            .feeds
+            .urls
+            .iter()
+            .find(|entry| entry.url() == url)
+            .map(|entry| gruik_config_guarded.feed(entry))
+    }
+    // Human readable list of the feeds, as written in the config file
+    pub fn feeds_descriptions(&self) -> Vec<String> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .feeds
+            .urls
+            .iter()
+            .map(ToString::to_string)
+            .collect()
     }
     pub fn irc_delay(&self) -> Duration {
+        self.inner.lock().expect("Poisoned lock!").irc.delay.into()
+    }
+    pub fn irc_burst(&self) -> u32 {
+        self.inner.lock().expect("Poisoned lock!").irc.burst
+    }
+    pub fn irc_max_reply_lines(&self) -> usize {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .irc
+            .max_reply_lines
+    }
+    pub fn irc_shutdown_timeout(&self) -> Duration {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .irc
+            .shutdown_timeout
+            .into()
+    }
+    pub fn irc_quit_message(&self) -> String {
         self.inner
             .lock()
             .expect("Poisoned lock!")
             .irc
-            .delay
-            .try_into()
-            .map_or_else(|_| Duration::new(2, 0), |d| d)
+            .quit_message
+            .replace(['\r', '\n'], " ")
     }
     pub fn origin_color(&self) -> IrcColor {
         self.inner
@@ -378,6 +1232,16 @@ impl GruikConfig {
             .unwrap_or(&IrcColor::LightGrey)
             .clone()
     }
+    pub fn excerpt_color(&self) -> IrcColor {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .irc
+            .colors
+            .get("excerpt")
+            .unwrap_or(&IrcColor::Grey)
+            .clone()
+    }
     pub fn link_color(&self) -> IrcColor {
         self.inner
             .lock()
@@ -388,6 +1252,21 @@ impl GruikConfig {
             .unwrap_or(&IrcColor::LightBlue)
             .clone()
     }
+    // The template of a feed, in a channel (None for a private message)
+    pub fn template(&self, feed: &str, channel: Option<&str>) -> Template {
+        let gruik_config_guarded = self.inner.lock().expect("Poisoned lock!");
+        gruik_config_guarded
+            .feeds
+            .urls
+            .iter()
+            .find(|f| f.url() == feed)
+            .and_then(|f| f.config().template)
+            .or_else(|| channel.and_then(|c| gruik_config_guarded.irc.templates.get(c).cloned()))
+            .unwrap_or_else(|| gruik_config_guarded.irc.template.clone())
+    }
+    pub fn ops(&self) -> Vec<String> {
+        self.inner.lock().expect("Poisoned lock!").irc.ops.clone()
+    }
     pub fn is_ops(&self, user: &String) -> bool {
         self.inner
             .lock()
@@ -399,84 +1278,249 @@ impl GruikConfig {
     pub fn debug(&self) -> bool {
         self.inner.lock().expect("Poisoned lock!").irc.debug
     }
-    pub fn feeds_maxage(&self) -> chrono::Duration {
-        let std_duration: Duration = self
-            .inner
-            .lock()
-            .expect("Poisoned lock!")
-            .feeds
-            .maxage
-            .into();
-        chrono::Duration::from_std(std_duration).expect("Wrong conversion!")
-    }
     pub fn feeds_frequency(&self) -> Duration {
         self.inner
             .lock()
             .expect("Poisoned lock!")
             .feeds
             .frequency
-            .try_into()
-            .map_or_else(|_| Duration::new(10 * 60, 0), |d| d)
-    }
-    pub fn feeds_maxnews(&self) -> u16 {
-        self.inner.lock().expect("Poisoned lock!").feeds.maxnews
+            .into()
     }
     pub fn feeds_ringsize(&self) -> usize {
         self.inner.lock().expect("Poisoned lock!").feeds.ringsize
     }
-    pub fn addfeed(&self, url: String) {
-        if self
-            .inner
+    pub fn feeds_concurrency(&self) -> usize {
+        self.inner.lock().expect("Poisoned lock!").feeds.concurrency
+    }
+    // The store is only read at startup
+    pub fn store_backend(&self) -> StoreBackend {
+        let gruik_config_guarded = self.inner.lock().expect("Poisoned lock!");
+        gruik_config_guarded
+            .store
+            .as_ref()
+            .map_or_else(StoreBackend::default, |store| store.backend)
+    }
+    pub fn store_path(&self) -> String {
+        let gruik_config_guarded = self.inner.lock().expect("Poisoned lock!");
+        gruik_config_guarded
+            .store
+            .as_ref()
+            .and_then(|store| store.path.clone())
+            .unwrap_or_else(|| gruik_config_guarded.irc.channel.clone() + "-feed.db")
+    }
+    // None : no limit
+    pub fn store_max_age(&self) -> Option<Duration> {
+        let store = self.inner.lock().expect("Poisoned lock!").store.clone();
+        let max_age: Duration = store.unwrap_or_default().max_age.into();
+        (!max_age.is_zero()).then_some(max_age)
+    }
+    pub fn store_max_news(&self) -> Option<usize> {
+        let store = self.inner.lock().expect("Poisoned lock!").store.clone();
+        Some(store.unwrap_or_default().max_news).filter(|&n| n != 0)
+    }
+    // The address of the HTTP server is only read at startup
+    pub fn http_bind(&self) -> Option<String> {
+        self.inner
             .lock()
             .expect("Poisoned lock!")
-            .feeds
-            .urls
-            .contains(&url)
-        {
-            return;
-        }
+            .http
+            .as_ref()
+            .map(|http| http.bind.clone())
+    }
+    pub fn http_title(&self) -> String {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .http
+            .as_ref()
+            .map_or_else(HttpConfig::title_default, |http| http.title.clone())
+    }
+    pub fn feeds_max_failures(&self) -> u32 {
         self.inner
             .lock()
             .expect("Poisoned lock!")
             .feeds
-            .urls
-            .push(url);
-        // We rewrite the config file with the new feed
-        match serde_yaml::to_string(&*self.inner.lock().expect("Poisoned lock")) {
-            Ok(s) => {
-                // Serialization is ok, writing the result to a file
-                match fs::write(&self.filename, s) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        println!("addfeed(): Failed to write the new config filename: {e}");
-                    }
-                }
-            }
-            Err(e) => println!("addfeed(): Failed to serialize GruikConfigYaml: {e}"),
-        }
+            .max_failures
     }
-    pub fn rmfeed(&self, index: usize) -> Result<(), String> {
-        if index > self.inner.lock().expect("Poisoned lock!").feeds.urls.len() {
-            return Err("bad index number".to_string());
-        }
+    pub fn feeds_timeout(&self) -> Duration {
         self.inner
             .lock()
             .expect("Poisoned lock!")
             .feeds
-            .urls
-            .remove(index);
-        // We rewrite the config file
-        match serde_yaml::to_string(&*self.inner.lock().expect("Poisoned lock")) {
-            Ok(s) => {
-                // Serialization is ok, writing the result to a file
-                match fs::write(&self.filename, s) {
-                    Ok(()) => Ok(()),
-                    Err(e) => Err(format!("rmfeed(): failed to write config file: {e}")),
+            .timeout
+            .into()
+    }
+    // Adds the feeds that are valid and not already in the list. Returns the number of feeds
+    // added, and why the other ones were skipped
+    pub fn import_feeds(
+        &self,
+        feeds: Vec<NewFeed>,
+    ) -> Result<(ConfigChanges, usize, Vec<String>), String> {
+        let (changes, skipped) = self
+            .change_feeds(|gruik_config| {
+                let mut skipped = Vec::new();
+                let mut items = Vec::new();
+                for feed in feeds {
+                    let entry = if feed.name.is_none() && feed.channels.is_empty() && feed.enabled {
+                        FeedEntry::Url(feed.url)
+                    } else {
+                        FeedEntry::Detailed(Box::new(FeedConfig {
+                            name: feed.name,
+                            channels: feed.channels,
+                            enabled: feed.enabled,
+                            ..FeedConfig::new(feed.url)
+                        }))
+                    };
+                    if let Err(e) = check_feed(&entry.config()) {
+                        skipped.push(e);
+                        continue;
+                    }
+                    if gruik_config
+                        .feeds
+                        .urls
+                        .iter()
+                        .any(|f| f.url() == entry.url())
+                    {
+                        skipped.push(format!("'{}' : already in the list", entry.url()));
+                        continue;
+                    }
+                    items.push(serde_yaml::to_string(&entry).map_err(|e| e.to_string())?);
+                    gruik_config.feeds.urls.push(entry);
                 }
-            }
-            Err(e) => Err(format!(
-                "rmfeed(): failed to serialize GruikConfigYaml: {e}"
-            )),
+                Ok((SeqEdit::Append(items), skipped))
+            })
+            .map_err(|e| format!("import_feeds(): {e}"))?;
+        let added = changes.feeds_added;
+        Ok((changes, added, skipped))
+    }
+    /*
+     * Changes the feeds list of a copy of the configuration, and rewrites the list in the config
+     * file (keeping everything else as it is). The new configuration is only used once the file
+     * is written. 'change' returns the edit to do in the file, and its own result
+     */
+    fn change_feeds<R>(
+        &self,
+        change: impl FnOnce(&mut GruikConfigYaml) -> Result<(SeqEdit, R), String>,
+    ) -> Result<(ConfigChanges, R), String> {
+        // Same lock order as reload(): 'file_hash' first, then 'inner'
+        let mut known_hash = self.file_hash.lock().expect("Poisoned lock!");
+        let mut gruik_config_guarded = self.inner.lock().expect("Poisoned lock!");
+        let mut gruik_config = gruik_config_guarded.clone();
+        let (edit, result) = change(&mut gruik_config)?;
+        let changes = ConfigChanges::new(&gruik_config_guarded, &gruik_config);
+        if matches!(&edit, SeqEdit::Append(items) if items.is_empty()) {
+            return Ok((changes, result));
         }
+
+        let current = fs::read_to_string(&self.filename)
+            .map_err(|e| format!("Can't read '{}' : {e}", self.filename))?;
+        // We make sure that the edited file says what we think it says
+        let yaml = yaml_edit::edit_sequence(&current, "feeds", "urls", edit)
+            .and_then(
+                |yaml| match serde_yaml::from_str::<GruikConfigYaml>(&yaml) {
+                    Ok(r) if r.feeds.urls == gruik_config.feeds.urls => Ok(yaml),
+                    Ok(_) => Err("the result doesn't match the feeds list".to_string()),
+                    Err(e) => Err(e.to_string()),
+                },
+            )
+            .map_err(|e| format!("Can't edit '{}' in place : {e}", self.filename))?;
+
+        write_atomically(&self.filename, &yaml)?;
+        // The file watcher doesn't have to reload what we just wrote
+        *known_hash = file_hash(&yaml);
+        *gruik_config_guarded = gruik_config;
+        Ok((changes, result))
+    }
+    // Options are 'key=value' strings, the keys being the ones of a detailed feed
+    pub fn addfeed(&self, url: String, options: &[&str]) -> Result<ConfigChanges, String> {
+        let entry = if options.is_empty() {
+            FeedEntry::Url(url)
+        } else {
+            FeedEntry::Detailed(Box::new(FeedConfig::from_options(url, options)?))
+        };
+        check_feed(&entry.config())?;
+        let item = serde_yaml::to_string(&entry).map_err(|e| e.to_string())?;
+        self.change_feeds(|gruik_config| {
+            if gruik_config
+                .feeds
+                .urls
+                .iter()
+                .any(|f| f.url() == entry.url())
+            {
+                return Err("this feed is already in the list".to_string());
+            }
+            gruik_config.feeds.urls.push(entry);
+            Ok((SeqEdit::Append(vec![item]), ()))
+        })
+        .map(|(changes, ())| changes)
+        .map_err(|e| format!("addfeed(): {e}"))
+    }
+    pub fn rmfeed(&self, index: usize) -> Result<ConfigChanges, String> {
+        self.change_feeds(|gruik_config| {
+            if index >= gruik_config.feeds.urls.len() {
+                return Err("bad index number".to_string());
+            }
+            gruik_config.feeds.urls.remove(index);
+            Ok((SeqEdit::Remove(index), ()))
+        })
+        .map(|(changes, ())| changes)
+        .map_err(|e| format!("rmfeed(): {e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_file(name: &str, yaml: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("gruik-test-{}-{name}.yml", std::process::id()));
+        fs::write(&path, yaml).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn feeds_are_edited_in_place() {
+        let yaml = "irc:\n  server: irc.example.org # ours\n  nick: gruik\n\
+                    feeds:\n  # the feeds\n  urls:\n    - https://a.example/feed\n";
+        let filename = config_file("in-place", yaml);
+        let gruik_config = GruikConfig::new(filename.clone());
+
+        let changes = gruik_config
+            .addfeed("https://b.example/feed".to_string(), &[])
+            .unwrap();
+        assert_eq!(changes.feeds_added, 1);
+        let changes = gruik_config.rmfeed(0).unwrap();
+        assert_eq!(changes.feeds_removed, 1);
+
+        let urls: Vec<String> = gruik_config.feeds().into_iter().map(|f| f.url).collect();
+        assert_eq!(urls, vec!["https://b.example/feed".to_string()]);
+        let written = fs::read_to_string(&filename).unwrap();
+        assert!(written.contains("# ours"));
+        assert!(written.contains("# the feeds"));
+        assert!(written.contains("- https://b.example/feed"));
+        // The file watcher won't reload it
+        assert!(gruik_config.reload().unwrap().is_none());
+        let _ = fs::remove_file(&filename);
+    }
+
+    // Nothing changes when the file can't be edited
+    #[test]
+    fn failed_edits_change_nothing() {
+        let yaml = "irc: {server: irc.example.org, nick: gruik}\n\
+                    feeds: {urls: [https://a.example/feed]}\n";
+        let filename = config_file("failed", yaml);
+        let gruik_config = GruikConfig::new(filename.clone());
+
+        assert!(gruik_config.rmfeed(0).is_err());
+        assert!(
+            gruik_config
+                .addfeed("https://b.example/feed".to_string(), &[])
+                .is_err()
+        );
+        let urls: Vec<String> = gruik_config.feeds().into_iter().map(|f| f.url).collect();
+        assert_eq!(urls, vec!["https://a.example/feed".to_string()]);
+        assert_eq!(fs::read_to_string(&filename).unwrap(), yaml);
+        let _ = fs::remove_file(&filename);
     }
 }
@@ -0,0 +1,149 @@
+/*
+ * Plain text from the texts of the feeds
+ *
+ * Titles and summaries often are HTML (or were escaped twice) : we strip the tags, decode the
+ * entities and collapse the whitespace. Nothing that could break an IRC line (CR, LF, or the
+ * formatting control characters) survives.
+ */
+
+use regex::{Captures, Regex};
+use std::sync::LazyLock;
+
+static TAG: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?is)<script\b.*?</script\s*>|<style\b.*?</style\s*>|<!--.*?-->|<!\[CDATA\[|\]\]>|</?([a-z][a-z0-9]*)\b[^>]*>|<![^>]*>",
+    )
+    .expect("Wrong regex!")
+});
+static ENTITY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"&(?:#([0-9]{1,7})|#[xX]([0-9a-fA-F]{1,6})|([a-zA-Z][a-zA-Z0-9]{1,31}));")
+        .expect("Wrong regex!")
+});
+
+static PARAGRAPH: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<p\b[^>]*>(.*?)</p\s*>").expect("Wrong regex!"));
+static BLANK_LINE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\n[ \t\r]*\n").expect("Wrong regex!"));
+
+// These tags separate words
+const BLOCK_TAGS: [&str; 22] = [
+    "address",
+    "article",
+    "blockquote",
+    "br",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "p",
+    "pre",
+    "section",
+    "td",
+    "tr",
+];
+
+// The entities seen in the wild (the numeric ones are decoded too)
+#[rustfmt::skip]
+const ENTITIES: [(&str, &str); 64] = [
+    ("amp", "&"), ("lt", "<"), ("gt", ">"), ("quot", "\""), ("apos", "'"), ("nbsp", " "),
+    ("ensp", " "), ("emsp", " "), ("thinsp", " "), ("shy", ""), ("zwj", ""), ("zwnj", ""),
+    ("hellip", "…"), ("mdash", "—"), ("ndash", "–"), ("minus", "−"), ("bull", "•"),
+    ("middot", "·"), ("lsquo", "‘"), ("rsquo", "’"), ("sbquo", "‚"), ("ldquo", "“"),
+    ("rdquo", "”"), ("bdquo", "„"), ("laquo", "«"), ("raquo", "»"), ("lsaquo", "‹"),
+    ("rsaquo", "›"), ("copy", "©"), ("reg", "®"), ("trade", "™"), ("deg", "°"),
+    ("plusmn", "±"), ("times", "×"), ("divide", "÷"), ("frac12", "½"), ("euro", "€"),
+    ("pound", "£"), ("yen", "¥"), ("cent", "¢"), ("sect", "§"), ("para", "¶"),
+    ("dagger", "†"), ("larr", "←"), ("rarr", "→"), ("uarr", "↑"), ("darr", "↓"),
+    ("agrave", "à"), ("aacute", "á"), ("acirc", "â"), ("auml", "ä"), ("ccedil", "ç"),
+    ("egrave", "è"), ("eacute", "é"), ("ecirc", "ê"), ("euml", "ë"), ("icirc", "î"),
+    ("iuml", "ï"), ("ocirc", "ô"), ("ouml", "ö"), ("ugrave", "ù"), ("ucirc", "û"),
+    ("uuml", "ü"), ("szlig", "ß"),
+];
+
+fn decode_entity(caps: &Captures) -> String {
+    let code = if let Some(dec) = caps.get(1) {
+        dec.as_str().parse().ok()
+    } else if let Some(hex) = caps.get(2) {
+        u32::from_str_radix(hex.as_str(), 16).ok()
+    } else {
+        let name = &caps[3];
+        // Uppercase letters have the same names, with a capital first letter (&Eacute;)
+        let lower = name.to_lowercase();
+        return match ENTITIES.iter().find(|(n, _)| *n == name || *n == lower) {
+            Some((n, value)) if *n != name => value.to_uppercase(),
+            Some((_, value)) => (*value).to_string(),
+            None => caps[0].to_string(),
+        };
+    };
+    code.and_then(char::from_u32)
+        .map_or_else(String::new, |c| c.to_string())
+}
+
+pub fn to_plain_text(html: &str) -> String {
+    let text = TAG.replace_all(html, |caps: &Captures| match caps.get(1) {
+        Some(name) if BLOCK_TAGS.contains(&name.as_str().to_lowercase().as_str()) => " ",
+        _ => "",
+    });
+    let text = ENTITY.replace_all(&text, decode_entity);
+    // The control characters that are not whitespace (IRC colors for example) are dropped
+    text.split(|c: char| c.is_whitespace())
+        .map(|word| word.chars().filter(|c| !c.is_control()).collect::<String>())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// A link can't have whitespace, and its control characters would end up on IRC
+pub fn clean_link(href: &str) -> String {
+    href.chars()
+        .filter(|c| !c.is_control() && !c.is_whitespace())
+        .collect()
+}
+
+// The plain text of the first paragraph of an HTML (or plain text) document
+pub fn first_paragraph(html: &str) -> String {
+    let paragraphs: Vec<&str> = if PARAGRAPH.is_match(html) {
+        PARAGRAPH
+            .captures_iter(html)
+            .filter_map(|caps| caps.get(1))
+            .map(|p| p.as_str())
+            .collect()
+    } else {
+        BLANK_LINE.split(html).collect()
+    };
+    paragraphs
+        .into_iter()
+        .map(to_plain_text)
+        .find(|p| !p.is_empty())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(
+            to_plain_text("<p>Fish &amp; chips</p>\r\n<p>\x02bold\x0f</p>"),
+            "Fish & chips bold"
+        );
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            clean_link(" https://example.org/\x02a\x03b\x0f\r\nPRIVMSG #chan :hi "),
+            "https://example.org/abPRIVMSG#chan:hi"
+        );
+    }
+}
@@ -0,0 +1,203 @@
+/*
+ * Conditional fetching of the feeds
+ *
+ * The ETag and Last-Modified headers of the last response are sent back to the server, which
+ * answers '304 Not Modified' when the feed didn't change. The server can also tell us when to
+ * come back (Cache-Control: max-age, Retry-After).
+ */
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use ureq::http::{HeaderMap, StatusCode, header};
+
+use crate::feed_map::FeedMap;
+
+// A server asking us to wait for more than this is probably misconfigured
+const MAX_WAIT: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Validators {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(ToString::to_string)
+        };
+        Self {
+            etag: get(header::ETAG),
+            last_modified: get(header::LAST_MODIFIED),
+        }
+    }
+}
+
+pub struct Response {
+    // None when the feed didn't change since the last fetch
+    pub body: Option<Vec<u8>>,
+    pub validators: Validators,
+    // How long the server wants us to wait before fetching the feed again
+    pub wait: Option<Duration>,
+}
+
+pub struct Error {
+    pub reason: String,
+    pub wait: Option<Duration>,
+}
+
+// The validators of every feed, saved to disk to survive restarts
+pub type HttpCache = FeedMap<Validators>;
+
+fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(header::CACHE_CONTROL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|directive| {
+            let (name, value) = directive.trim().split_once('=')?;
+            name.eq_ignore_ascii_case("max-age")
+                .then(|| value.trim_matches('"').parse().ok())?
+        })
+        .map(Duration::from_secs)
+}
+
+// Retry-After is either a number of seconds or a date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date: DateTime<Utc> = DateTime::parse_from_rfc2822(value).ok()?.into();
+    (date - Utc::now()).to_std().ok()
+}
+
+pub fn fetch(url: &str, cache: &HttpCache, timeout: Duration) -> Result<Response, Error> {
+    let validators = cache.get(url);
+
+    let mut request = ureq::get(url);
+    if let Some(etag) = &validators.etag {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let mut response = request
+        .config()
+        .http_status_as_error(false)
+        .timeout_global(Some(timeout))
+        .build()
+        .call()
+        .map_err(|e| Error {
+            reason: format!("Failed to get a response : {e:?}"),
+            wait: None,
+        })?;
+
+    let status = response.status();
+    let headers = response.headers();
+    let wait = retry_after(headers)
+        .filter(|_| {
+            status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+        })
+        .or_else(|| max_age(headers))
+        .map(|wait| wait.min(MAX_WAIT));
+
+    if status == StatusCode::NOT_MODIFIED {
+        return Ok(Response {
+            body: None,
+            validators,
+            wait,
+        });
+    }
+    if !status.is_success() {
+        return Err(Error {
+            reason: format!("Failed to get a response : {status}"),
+            wait,
+        });
+    }
+
+    let validators = Validators::from_headers(headers);
+    let body = response.body_mut().read_to_vec().map_err(|e| Error {
+        reason: format!("Failed to read the response : {e:?}"),
+        wait,
+    })?;
+    Ok(Response {
+        body: Some(body),
+        validators,
+        wait,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // A local HTTP stand-in giving these responses in turn, it sends back the headers of the
+    // requests it got (lowercase)
+    fn http_server(responses: Vec<&'static str>) -> (String, mpsc::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/feed.xml", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (response, tcp) in responses.into_iter().zip(listener.incoming()) {
+                let mut tcp = BufReader::new(tcp.unwrap());
+                let mut headers = vec![];
+                let mut line = String::new();
+                while tcp.read_line(&mut line).is_ok_and(|n| n > 0) && line.trim() != "" {
+                    headers.push(line.trim().to_lowercase());
+                    line.clear();
+                }
+                let _ = tx.send(headers);
+                let _ = tcp.get_mut().write_all(response.as_bytes());
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn conditional_fetch() {
+        let (url, requests) = http_server(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Wed, 14 Oct 2026 10:00:00 GMT\r\n\
+             Cache-Control: public, max-age=600\r\nContent-Length: 4\r\nConnection: close\r\n\
+             \r\nfeed",
+            "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 120\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+        ]);
+        let cache = HttpCache::new();
+        let timeout = Duration::from_secs(5);
+
+        let Ok(response) = fetch(&url, &cache, timeout) else {
+            panic!("the first fetch failed");
+        };
+        assert_eq!(response.body.as_deref(), Some(b"feed".as_slice()));
+        assert_eq!(response.wait, Some(Duration::from_secs(600)));
+        let headers = requests.recv().unwrap();
+        assert!(!headers.iter().any(|h| h.starts_with("if-none-match:")));
+        cache.set(&url, response.validators);
+
+        let Ok(response) = fetch(&url, &cache, timeout) else {
+            panic!("the second fetch failed");
+        };
+        assert!(response.body.is_none());
+        let headers = requests.recv().unwrap();
+        assert!(headers.contains(&"if-none-match: \"v1\"".to_string()));
+        assert!(headers.contains(&"if-modified-since: wed, 14 oct 2026 10:00:00 gmt".to_string()));
+
+        let Err(error) = fetch(&url, &cache, timeout) else {
+            panic!("the third fetch didn't fail");
+        };
+        assert_eq!(error.wait, Some(Duration::from_secs(120)));
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ * A minimal HTTP server, serving the news we collected
+ *
+ * /feed.json : JSON Feed 1.1 (https://www.jsonfeed.org/version/1.1/)
+ * /rss.xml   : RSS 2.0
+ * /atom.xml  : Atom
+ *
+ * The news can be filtered with '?origin=...' and '?channel=...' (the '#' has to be written %23)
+ */
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::gruik_config::GruikConfig;
+use crate::{News, NewsList};
+
+// We only read the request line and the headers
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Request {
+    method: String,
+    path: String,
+    origin: Option<String>,
+    channel: Option<String>,
+    // Used to build absolute URLs
+    host: String,
+}
+
+fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut origin = None;
+    let mut channel = None;
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match &*key {
+            "origin" => origin = Some(value.into_owned()),
+            "channel" => channel = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    let host = lines
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("host")
+                .then(|| value.trim().to_string())
+        })
+        .unwrap_or_else(|| "localhost".to_string());
+
+    Some(Request {
+        method,
+        path: path.to_string(),
+        origin,
+        channel,
+        host,
+    })
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn link(news: &News) -> &str {
+    news.links.first().map_or("", String::as_str)
+}
+
+fn json_feed(title: &str, feed_url: &str, news_list: &[News]) -> String {
+    let items: Vec<serde_json::Value> = news_list
+        .iter()
+        .map(|news| {
+            serde_json::json!({
+                "id": news.hash,
+                "url": link(news),
+                "title": news.title,
+                "content_text": news.title,
+                "date_published": news.date.to_rfc3339(),
+                "authors": [{ "name": news.origin }],
+                "tags": news.channels,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": title,
+        "feed_url": feed_url,
+        "items": items,
+    })
+    .to_string()
+}
+
+fn rss(title: &str, feed_url: &str, news_list: &[News]) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <rss version=\"2.0\"><channel>\
+         <title>{}</title><link>{}</link><description>{}</description>",
+        xml_escape(title),
+        xml_escape(feed_url),
+        xml_escape(title),
+    );
+    for news in news_list {
+        xml += &format!(
+            "<item><title>{}</title><link>{}</link><guid isPermaLink=\"false\">{}</guid>\
+             <pubDate>{}</pubDate><source url=\"{}\">{}</source>",
+            xml_escape(&news.title),
+            xml_escape(link(news)),
+            xml_escape(&news.hash),
+            news.date.to_rfc2822(),
+            xml_escape(&news.feed),
+            xml_escape(&news.origin),
+        );
+        for channel in &news.channels {
+            xml += &format!("<category>{}</category>", xml_escape(channel));
+        }
+        xml += "</item>";
+    }
+    xml + "</channel></rss>\n"
+}
+
+fn atom(title: &str, feed_url: &str, news_list: &[News]) -> String {
+    let updated = news_list
+        .iter()
+        .map(|news| news.date)
+        .max()
+        .unwrap_or_else(chrono::Utc::now);
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\
+         <title>{}</title><id>{}</id><link rel=\"self\" href=\"{}\"/><updated>{}</updated>",
+        xml_escape(title),
+        xml_escape(feed_url),
+        xml_escape(feed_url),
+        updated.to_rfc3339(),
+    );
+    for news in news_list {
+        xml += &format!(
+            "<entry><title>{}</title><id>urn:gruik:{}</id><link href=\"{}\"/>\
+             <updated>{}</updated><author><name>{}</name></author>",
+            xml_escape(&news.title),
+            xml_escape(&news.hash),
+            xml_escape(link(news)),
+            news.date.to_rfc3339(),
+            xml_escape(&news.origin),
+        );
+        for channel in &news.channels {
+            xml += &format!("<category term=\"{}\"/>", xml_escape(channel));
+        }
+        xml += "</entry>";
+    }
+    xml + "</feed>\n"
+}
+
+// Returns the status, the content type and the body of the response
+fn respond(
+    gruik_config: &GruikConfig,
+    news_list: &NewsList,
+    request: &Request,
+) -> (&'static str, &'static str, String) {
+    if request.method != "GET" && request.method != "HEAD" {
+        return (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        );
+    }
+    let format = match request.path.as_str() {
+        "/feed.json" => json_feed,
+        "/rss.xml" => rss,
+        "/atom.xml" => atom,
+        _ => return ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let content_type = match request.path.as_str() {
+        "/feed.json" => "application/feed+json",
+        "/rss.xml" => "application/rss+xml",
+        _ => "application/atom+xml",
+    };
+
+    let origin: Vec<&str> = request.origin.as_deref().into_iter().collect();
+    let news = news_list.get_latest(usize::MAX, request.channel.as_deref(), &origin);
+    let feed_url = format!("http://{}{}", request.host, request.path);
+    (
+        "200 OK",
+        content_type,
+        format(&gruik_config.http_title(), &feed_url, &news),
+    )
+}
+
+async fn handle_connection(
+    gruik_config: &GruikConfig,
+    news_list: &NewsList,
+    stream: &mut TcpStream,
+) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(std::io::Error::other("request too large"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let (status, content_type, body, head_only) = match parse_request(&head) {
+        Some(request) => {
+            let (status, content_type, body) = respond(gruik_config, news_list, &request);
+            (status, content_type, body, request.method == "HEAD")
+        }
+        None => (
+            "400 Bad Request",
+            "text/plain",
+            "bad request\n".to_string(),
+            false,
+        ),
+    };
+    // A HEAD response has the length of the body, without the body
+    let len = body.len();
+    let body = if head_only { "" } else { body.as_str() };
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: {content_type}; charset=utf-8\r\n\
+                 Content-Length: {len}\r\nConnection: close\r\n\r\n{body}"
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await
+}
+
+pub async fn run(gruik_config: GruikConfig, news_list: NewsList, listener: TcpListener) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("HTTP server : accept() failed : {e}");
+                continue;
+            }
+        };
+        let gruik_config = gruik_config.clone();
+        let news_list = news_list.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(
+                REQUEST_TIMEOUT,
+                handle_connection(&gruik_config, &news_list, &mut stream),
+            )
+            .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => println!("HTTP server : {peer} : {e}"),
+                Err(_) => println!("HTTP server : {peer} : timeout"),
+            }
+        });
+    }
+}
@@ -0,0 +1,546 @@
+/*
+ * This is a replacement for loirc::connect() that can also speak TLS
+ *
+ * loirc only knows about plain TcpStreams, so the connection (and reconnection) handling is
+ * done here. Lines are still parsed with loirc::Message and the reader still receives
+ * loirc::Event, so the rest of the code doesn't have to know which transport is used.
+ */
+use encoding::{DecoderTrap, EncoderTrap, EncodingRef};
+use loirc::{Error, Event, Message, ReconnectionSettings};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+pub type Reader = mpsc::Receiver<Event>;
+
+pub struct TlsSettings {
+    // When false, the server certificate is not checked at all (self-signed certs, ...)
+    pub verify: bool,
+    // PEM file containing additional CA certificates to trust
+    pub ca_file: Option<String>,
+    // Client certificate and its key (PEM), used by SASL EXTERNAL
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+}
+
+enum Transport {
+    Plain(TcpStream),
+    Tls(TcpStream, Box<ClientConnection>),
+}
+
+impl Transport {
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Plain(tcp) => tcp.write_all(bytes),
+            Self::Tls(tcp, conn) => {
+                conn.writer().write_all(bytes)?;
+                while conn.wants_write() {
+                    conn.write_tls(tcp)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        match self {
+            Self::Plain(tcp) => {
+                let _ = tcp.shutdown(Shutdown::Both);
+            }
+            Self::Tls(tcp, conn) => {
+                conn.send_close_notify();
+                let _ = conn.write_tls(tcp);
+                let _ = tcp.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    // Turns bytes read from the socket into plaintext
+    fn decrypt(&mut self, mut bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let (tcp, conn) = match self {
+            Self::Plain(_) => return Ok(bytes.to_vec()),
+            Self::Tls(tcp, conn) => (tcp, conn),
+        };
+
+        let mut plaintext = Vec::new();
+        while !bytes.is_empty() {
+            conn.read_tls(&mut bytes)?;
+            conn.process_new_packets().map_err(io::Error::other)?;
+            let mut buf = [0u8; 4096];
+            loop {
+                match conn.reader().read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => plaintext.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        // TLS may have something to say back (key updates, alerts, ...)
+        while conn.wants_write() {
+            conn.write_tls(tcp)?;
+        }
+        Ok(plaintext)
+    }
+}
+
+enum StreamStatus {
+    Closed,
+    Connected(Transport),
+    Disconnected,
+}
+
+// Used to send messages to the IRC server, it can be cloned and shared between threads
+#[derive(Clone)]
+pub struct Writer {
+    stream: Arc<Mutex<StreamStatus>>,
+    connector: Arc<Mutex<Connector>>,
+    encoding: EncodingRef,
+}
+
+impl Writer {
+    fn set_connected(&self, transport: Transport) {
+        *self.stream.lock().expect("Poisoned lock!") = StreamStatus::Connected(transport);
+    }
+
+    fn set_disconnected(&self) {
+        *self.stream.lock().expect("Poisoned lock!") = StreamStatus::Disconnected;
+    }
+
+    fn decrypt(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        let mut status = self.stream.lock().expect("Poisoned lock!");
+        match *status {
+            StreamStatus::Connected(ref mut transport) => match transport.decrypt(bytes) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    println!("TLS error : {e}");
+                    transport.shutdown();
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    // Drops the connection and triggers the reconnection process
+    pub fn disconnect(&self) -> Result<(), Error> {
+        let mut status = self.stream.lock().expect("Poisoned lock!");
+        match *status {
+            StreamStatus::Closed => return Err(Error::Closed),
+            StreamStatus::Connected(ref mut transport) => transport.shutdown(),
+            StreamStatus::Disconnected => return Err(Error::AlreadyDisconnected),
+        }
+        *status = StreamStatus::Disconnected;
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(
+            *self.stream.lock().expect("Poisoned lock!"),
+            StreamStatus::Closed
+        )
+    }
+
+    // The next (re)connection will go to this server, call disconnect() to use it right away
+    pub fn set_server(&self, server: &str, port: u16, tls: Option<&TlsSettings>) -> io::Result<()> {
+        *self.connector.lock().expect("Poisoned lock!") = Connector::new(server, port, tls)?;
+        Ok(())
+    }
+
+    // Closes the connection for good (no reconnection attempt)
+    pub fn close(&self) -> Result<(), Error> {
+        let mut status = self.stream.lock().expect("Poisoned lock!");
+        match *status {
+            StreamStatus::Closed => return Err(Error::AlreadyClosed),
+            StreamStatus::Connected(ref mut transport) => transport.shutdown(),
+            StreamStatus::Disconnected => {}
+        }
+        *status = StreamStatus::Closed;
+        Ok(())
+    }
+
+    // Sends a raw string to the IRC server (the new line must be included)
+    pub fn raw<S: AsRef<str>>(&self, data: S) -> Result<(), Error> {
+        let mut status = self.stream.lock().expect("Poisoned lock!");
+        match *status {
+            StreamStatus::Closed => Err(Error::Closed),
+            StreamStatus::Disconnected => Err(Error::Disconnected),
+            StreamStatus::Connected(ref mut transport) => {
+                let bytes = self
+                    .encoding
+                    .encode(data.as_ref(), EncoderTrap::Ignore)
+                    .unwrap_or_default();
+                if transport.write_all(&bytes).is_err() {
+                    transport.shutdown();
+                    *status = StreamStatus::Disconnected;
+                    return Err(Error::Disconnected);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Everything needed to (re)open the connection
+#[derive(Clone)]
+struct Connector {
+    address: String,
+    server_name: ServerName<'static>,
+    tls_config: Option<Arc<ClientConfig>>,
+}
+
+impl Connector {
+    fn new(server: &str, port: u16, tls: Option<&TlsSettings>) -> io::Result<Self> {
+        let tls_config = match tls {
+            Some(settings) => Some(Arc::new(mk_tls_config(settings)?)),
+            None => None,
+        };
+        Ok(Self {
+            address: format!("{server}:{port}"),
+            server_name: ServerName::try_from(server.to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            tls_config,
+        })
+    }
+
+    // Returns the transport (used by the Writer) and a clone of the socket (used by the reader
+    // thread)
+    fn open(&self) -> io::Result<(Transport, TcpStream)> {
+        let mut tcp = TcpStream::connect(&self.address)?;
+        let reader = tcp.try_clone()?;
+        let transport = match &self.tls_config {
+            None => Transport::Plain(tcp),
+            Some(tls_config) => {
+                let mut conn = ClientConnection::new(tls_config.clone(), self.server_name.clone())
+                    .map_err(io::Error::other)?;
+                while conn.is_handshaking() {
+                    conn.complete_io(&mut tcp)?;
+                }
+                Transport::Tls(tcp, Box::new(conn))
+            }
+        };
+        Ok((transport, reader))
+    }
+}
+
+// Accepts any server certificate, this is what 'tls_verify: false' means
+#[derive(Debug)]
+struct NoVerification(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+fn read_client_cert(
+    cert_file: &str,
+    key_file: &str,
+) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(Iterator::collect)
+        .map_err(|e| io::Error::other(format!("Can't read '{cert_file}' : {e}")))?;
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| io::Error::other(format!("Can't read '{key_file}' : {e}")))?;
+    Ok((certs, key))
+}
+
+fn mk_tls_config(settings: &TlsSettings) -> io::Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+
+    let client_cert = match (&settings.cert_file, &settings.key_file) {
+        (Some(cert_file), Some(key_file)) => Some(read_client_cert(cert_file, key_file)?),
+        _ => None,
+    };
+
+    let builder = if settings.verify {
+        let mut roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(ca_file) = &settings.ca_file {
+            let certs = CertificateDer::pem_file_iter(ca_file)
+                .map_err(|e| io::Error::other(format!("Can't read '{ca_file}' : {e}")))?;
+            for cert in certs {
+                let cert =
+                    cert.map_err(|e| io::Error::other(format!("Can't parse '{ca_file}' : {e}")))?;
+                roots.add(cert).map_err(io::Error::other)?;
+            }
+        }
+        builder.with_root_certificates(roots)
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(
+                provider.signature_verification_algorithms,
+            )))
+    };
+
+    match client_cert {
+        Some((certs, key)) => builder
+            .with_client_auth_cert(certs, key)
+            .map_err(io::Error::other),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+// Same logic as loirc: returns a new reader once the connection is restored, or None when we
+// have to give up (or nobody is listening to the events anymore)
+fn reconnect(
+    event_sender: &mpsc::Sender<Event>,
+    handle: &Writer,
+    reco_settings: ReconnectionSettings,
+) -> Option<TcpStream> {
+    let ReconnectionSettings::Reconnect {
+        max_attempts,
+        delay_between_attempts,
+        delay_after_disconnect,
+    } = reco_settings
+    else {
+        let _ = handle.close();
+        let _ = event_sender.send(Event::Closed("do not reconnect"));
+        return None;
+    };
+
+    thread::sleep(delay_after_disconnect);
+
+    let mut attempts = 0u32;
+    loop {
+        // max_attempts == 0 means infinite attempts
+        if max_attempts > 0 {
+            attempts += 1;
+            if attempts > max_attempts {
+                let _ = handle.close();
+                let _ = event_sender.send(Event::Closed("max attempts reached"));
+                return None;
+            }
+        }
+
+        event_sender.send(Event::Reconnecting).ok()?;
+
+        // The server may have changed since the last connection
+        let connector = handle.connector.lock().expect("Poisoned lock!").clone();
+        match connector.open() {
+            Ok((transport, reader)) => {
+                handle.set_connected(transport);
+                event_sender.send(Event::Reconnected).ok()?;
+                return Some(reader);
+            }
+            Err(e) => {
+                event_sender.send(Event::ReconnectionError(e)).ok()?;
+            }
+        }
+        thread::sleep(delay_between_attempts);
+    }
+}
+
+fn reader_thread(
+    mut reader: TcpStream,
+    event_sender: &mpsc::Sender<Event>,
+    handle: &Writer,
+    reco_settings: ReconnectionSettings,
+    encoding: EncodingRef,
+) {
+    let mut buf = [0u8; 4096];
+    let mut line = Vec::new();
+
+    'read: loop {
+        let received = match reader.read(&mut buf) {
+            // A zero length read means that the socket was shutdown
+            Ok(0) | Err(_) => None,
+            Ok(n) => handle.decrypt(&buf[..n]),
+        };
+
+        let Some(received) = received else {
+            if handle.is_closed() {
+                let _ = event_sender.send(Event::Closed("manually closed"));
+                break;
+            }
+            handle.set_disconnected();
+            if event_sender.send(Event::Disconnected).is_err() {
+                break;
+            }
+            match reconnect(event_sender, handle, reco_settings) {
+                Some(r) => reader = r,
+                None => break,
+            }
+            line.clear();
+            continue;
+        };
+
+        line.extend_from_slice(&received);
+        while let Some(pos) = line.iter().position(|b| *b == b'\n') {
+            let raw: Vec<u8> = line.drain(..=pos).collect();
+            let decoded = encoding
+                .decode(&raw, DecoderTrap::Ignore)
+                .unwrap_or_default();
+            let event = match Message::parse(&decoded) {
+                Ok(msg) => Event::Message(msg),
+                Err(e) => Event::ParseError(e),
+            };
+            if event_sender.send(event).is_err() {
+                break 'read;
+            }
+        }
+    }
+
+    if !handle.is_closed() {
+        let _ = handle.close();
+    }
+}
+
+pub fn connect(
+    server: &str,
+    port: u16,
+    tls: Option<&TlsSettings>,
+    reco_settings: ReconnectionSettings,
+    encoding: EncodingRef,
+) -> io::Result<(Writer, Reader)> {
+    let connector = Connector::new(server, port, tls)?;
+    let (transport, reader) = connector.open()?;
+    let writer = Writer {
+        stream: Arc::new(Mutex::new(StreamStatus::Connected(transport))),
+        connector: Arc::new(Mutex::new(connector)),
+        encoding,
+    };
+    let (event_sender, event_reader) = mpsc::channel();
+
+    // The reader thread needs a handle to modify the status
+    let handle = writer.clone();
+    thread::spawn(move || {
+        reader_thread(reader, &event_sender, &handle, reco_settings, encoding);
+    });
+
+    Ok((writer, event_reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    // A local TLS IRC stand-in with a self-signed certificate, it answers a PING with a PONG.
+    // Returns its port and the certificate (PEM)
+    fn tls_server() -> (u16, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.cert.der().clone()], key.into())
+                .unwrap();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for tcp in listener.incoming().flatten() {
+                let config = config.clone();
+                thread::spawn(move || {
+                    let conn = ServerConnection::new(config).unwrap();
+                    let mut stream = BufReader::new(StreamOwned::new(conn, tcp));
+                    let mut line = String::new();
+                    // Fails when the client rejects our certificate
+                    while stream.read_line(&mut line).is_ok_and(|n| n > 0) {
+                        if let Some(token) = line.trim_end().strip_prefix("PING ") {
+                            let pong = format!("PONG {token}\r\n");
+                            let _ = stream.get_mut().write_all(pong.as_bytes());
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (port, cert.cert.pem())
+    }
+
+    fn tls_connect(
+        port: u16,
+        verify: bool,
+        ca_file: Option<String>,
+    ) -> io::Result<(Writer, Reader)> {
+        let settings = TlsSettings {
+            verify,
+            ca_file,
+            cert_file: None,
+            key_file: None,
+        };
+        connect(
+            "localhost",
+            port,
+            Some(&settings),
+            ReconnectionSettings::DoNotReconnect,
+            encoding::all::UTF_8,
+        )
+    }
+
+    fn round_trip(writer: &Writer, reader: &Reader) {
+        writer.raw("PING :gruik\n").unwrap();
+        match reader.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Event::Message(msg) => assert_eq!(msg.args, vec!["gruik".to_string()]),
+            event => panic!("unexpected event {event:?}"),
+        }
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn self_signed_certificate() {
+        let (port, cert_pem) = tls_server();
+
+        // Not in the default roots
+        assert!(tls_connect(port, true, None).is_err());
+
+        let ca_file = std::env::temp_dir().join(format!("gruik-test-ca-{port}.pem"));
+        std::fs::write(&ca_file, &cert_pem).unwrap();
+        let (writer, reader) =
+            tls_connect(port, true, Some(ca_file.to_string_lossy().to_string())).unwrap();
+        round_trip(&writer, &reader);
+        let _ = std::fs::remove_file(&ca_file);
+
+        let (writer, reader) = tls_connect(port, false, None).unwrap();
+        round_trip(&writer, &reader);
+    }
+}
@@ -0,0 +1,355 @@
+/*
+ * Everything we say on IRC goes through here (except during the registration, which has to
+ * happen before anything else anyway)
+ *
+ * A line is at most 512 bytes long, CR-LF and the prefix the server adds (":nick!user@host ")
+ * included. Longer lines are cut by the server, so we cut them ourselves, on char boundaries.
+ */
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::gruik_config::GruikConfig;
+use crate::irc_connection;
+use crate::irc_session::IrcSession;
+
+const MAX_LINE_LEN: usize = 512;
+const ELLIPSIS: &str = "…";
+
+// Returns the longest beginning of 's' that is at most 'max_len' bytes long
+pub fn truncate(s: &str, max_len: usize) -> &str {
+    let mut end = max_len.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+// Shortens 's' to at most 'max_len' bytes, ending with an ellipsis when it was cut
+pub fn ellipsize(s: &str, max_len: usize) -> String {
+    if s.len() <= max_len {
+        return s.to_string();
+    }
+    match max_len.checked_sub(ELLIPSIS.len()) {
+        Some(len) => format!("{}{ELLIPSIS}", truncate(s, len).trim_end()),
+        None => String::new(),
+    }
+}
+
+struct Line {
+    text: String,
+    // Replies to commands can be dropped, the news can't
+    reply: bool,
+}
+
+#[derive(Default)]
+struct Queues {
+    // Protocol messages (PONG, JOIN...), sent before anything else
+    urgent: VecDeque<String>,
+    normal: VecDeque<Line>,
+    // We are shutting down, only the protocol messages are still taken
+    closed: bool,
+    // A line was taken by send_loop() and is being written
+    writing: bool,
+}
+
+/*
+ * The queue of the lines to send, shared by everything that talks to the server
+ *
+ * The lines are written by send_loop(), which makes sure we don't flood the server : we can
+ * send irc.burst lines at once, then we earn the right to send one more line every irc.delay.
+ * The normal lines wait until we are registered.
+ */
+#[derive(Clone)]
+pub struct OutputQueue {
+    inner: Arc<(Mutex<Queues>, Condvar)>,
+    gruik_config: GruikConfig,
+    irc_session: IrcSession,
+}
+
+impl OutputQueue {
+    pub fn new(gruik_config: GruikConfig, irc_session: IrcSession) -> Self {
+        Self {
+            inner: Arc::default(),
+            gruik_config,
+            irc_session,
+        }
+    }
+    fn push(&self, line: Line) {
+        let (queues, condvar) = &*self.inner;
+        let mut queues = queues.lock().expect("Poisoned lock!");
+        if queues.closed {
+            return;
+        }
+        queues.normal.push_back(line);
+        condvar.notify_one();
+    }
+    // Sends a protocol message, ahead of the other lines
+    pub fn send_urgent(&self, line: String) {
+        let (queues, condvar) = &*self.inner;
+        queues
+            .lock()
+            .expect("Poisoned lock!")
+            .urgent
+            .push_back(line);
+        condvar.notify_one();
+    }
+    // How many bytes of text fit in a message to 'target'
+    pub fn budget(&self, target: &str) -> usize {
+        MAX_LINE_LEN
+            .saturating_sub(self.irc_session.prefix_len() + "PRIVMSG  :\r\n".len() + target.len())
+    }
+    // Splits 'text' in as many lines as needed (between words if possible)
+    fn privmsg_lines(&self, target: &str, text: &str) -> Vec<String> {
+        // We need room for at least one char
+        let budget = self.budget(target).max(4);
+        let mut lines = Vec::new();
+        for mut rest in text.split(['\r', '\n']) {
+            while !rest.is_empty() {
+                let mut line = truncate(rest, budget);
+                if line.len() < rest.len()
+                    && let Some(i) = line.rfind(' ').filter(|&i| i > 0)
+                {
+                    line = &line[..i];
+                }
+                lines.push(format!("PRIVMSG {target} :{line}\n"));
+                rest = rest[line.len()..].trim_start();
+            }
+        }
+        lines
+    }
+    // Sends 'text' to 'target'
+    pub fn privmsg(&self, target: &str, text: &str) {
+        for text in self.privmsg_lines(target, text) {
+            self.push(Line { text, reply: false });
+        }
+    }
+    /*
+     * Sends the reply to a command
+     *
+     * Only irc.max_reply_lines lines of replies can wait in the queue : when there is no room
+     * for the whole reply, we send what we can, and say how many lines were dropped
+     */
+    pub fn reply(&self, target: &str, texts: &[String]) {
+        let lines: Vec<String> = texts
+            .iter()
+            .flat_map(|text| self.privmsg_lines(target, text))
+            .collect();
+        let (queues, condvar) = &*self.inner;
+        let mut queues = queues.lock().expect("Poisoned lock!");
+        if queues.closed {
+            return;
+        }
+        let waiting = queues.normal.iter().filter(|line| line.reply).count();
+        let room = self
+            .gruik_config
+            .irc_max_reply_lines()
+            .saturating_sub(waiting);
+        if room == 0 {
+            println!("Too many replies waiting, dropping a reply to {target}");
+            return;
+        }
+        let kept = if lines.len() > room {
+            room - 1
+        } else {
+            lines.len()
+        };
+        for text in lines.iter().take(kept).cloned() {
+            queues.normal.push_back(Line { text, reply: true });
+        }
+        if kept < lines.len() {
+            queues.normal.push_back(Line {
+                text: format!(
+                    "PRIVMSG {target} :... {} line(s) dropped\n",
+                    lines.len() - kept
+                ),
+                reply: true,
+            });
+        }
+        condvar.notify_one();
+    }
+    // Stops taking lines, except the protocol messages
+    pub fn close(&self) {
+        self.inner.0.lock().expect("Poisoned lock!").closed = true;
+    }
+    // Waits (at most 'timeout') until everything that can be sent is sent
+    pub async fn flush(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            {
+                let queues = self.inner.0.lock().expect("Poisoned lock!");
+                if !queues.writing
+                    && queues.urgent.is_empty()
+                    && (queues.normal.is_empty() || !self.irc_session.is_registered())
+                {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+    // Forgets the lines still waiting, returns how many there were
+    pub fn clear(&self) -> usize {
+        let mut queues = self.inner.0.lock().expect("Poisoned lock!");
+        let n = queues.normal.len();
+        queues.normal.clear();
+        n
+    }
+    // Waits for the next line to send (None if there is none for now)
+    fn pop(&self) -> Option<String> {
+        let (queues, condvar) = &*self.inner;
+        let mut queues = queues.lock().expect("Poisoned lock!");
+        for _ in 0..2 {
+            if let Some(line) = queues.urgent.pop_front() {
+                queues.writing = true;
+                return Some(line);
+            }
+            if self.irc_session.is_registered()
+                && let Some(line) = queues.normal.pop_front()
+            {
+                queues.writing = true;
+                return Some(line.text);
+            }
+            // We also wake up to check if we got registered
+            queues = condvar
+                .wait_timeout(queues, Duration::from_millis(500))
+                .expect("Poisoned lock!")
+                .0;
+        }
+        None
+    }
+}
+
+/*
+ * This function runs in its own thread
+ */
+pub fn send_loop(irc_writer: &irc_connection::Writer, output_queue: &OutputQueue) {
+    let gruik_config = &output_queue.gruik_config;
+    let mut tokens = f64::from(gruik_config.irc_burst());
+    let mut last_refill = Instant::now();
+    loop {
+        // We earn one token every irc.delay, up to irc.burst
+        let delay = gruik_config.irc_delay().as_secs_f64();
+        let burst = f64::from(gruik_config.irc_burst());
+        tokens = burst.min(tokens + last_refill.elapsed().as_secs_f64() / delay);
+        last_refill = Instant::now();
+        if tokens < 1.0 {
+            thread::sleep(Duration::from_secs_f64((1.0 - tokens) * delay));
+            continue;
+        }
+        let Some(line) = output_queue.pop() else {
+            continue;
+        };
+        tokens -= 1.0;
+        if let Err(e) = irc_writer.raw(line) {
+            println!("Failed to send an IRC message... ({e:?})");
+        }
+        output_queue.inner.0.lock().expect("Poisoned lock!").writing = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output_queue(name: &str) -> OutputQueue {
+        let path = std::env::temp_dir().join(format!(
+            "gruik-test-{}-output-{name}.yml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "irc:\n  server: irc.example.org\n  nick: gruik\n  max_reply_lines: 3\nfeeds:\n  urls: []\n",
+        )
+        .unwrap();
+        let gruik_config = GruikConfig::new(path.to_string_lossy().to_string());
+        let _ = std::fs::remove_file(&path);
+        let irc_session = IrcSession::new();
+        irc_session.set_registered("gruik".to_string());
+        irc_session.set_user_host("~gruik".to_string(), "example.org".to_string());
+        OutputQueue::new(gruik_config, irc_session)
+    }
+
+    #[test]
+    fn multibyte_chars_are_not_cut() {
+        // 'é' is 2 bytes long, the cut point falls in its middle
+        assert_eq!(truncate("aé", 2), "a");
+        assert_eq!(truncate("aé", 3), "aé");
+        assert_eq!(truncate("aé", 10), "aé");
+        let s = ellipsize("aaaa éééé", 8);
+        assert_eq!(s, "aaaa…");
+        assert_eq!(ellipsize("aaaa", 4), "aaaa");
+        // No room for the ellipsis itself
+        assert_eq!(ellipsize("aaaa", 2), "");
+
+        let output_queue = output_queue("multibyte");
+        let budget = output_queue.budget("#chan");
+        let text = format!("{}é", "a".repeat(budget - 1));
+        let lines = output_queue.privmsg_lines("#chan", &text);
+        assert_eq!(
+            lines,
+            vec![
+                format!("PRIVMSG #chan :{}\n", "a".repeat(budget - 1)),
+                "PRIVMSG #chan :é\n".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn exact_fit() {
+        let output_queue = output_queue("exact");
+        let prefix_len = ":gruik!~gruik@example.org ".len();
+        let budget = output_queue.budget("#chan");
+
+        let lines = output_queue.privmsg_lines("#chan", &"a".repeat(budget));
+        assert_eq!(lines.len(), 1);
+        // The line is sent with CR-LF
+        assert_eq!(prefix_len + lines[0].len() + 1, MAX_LINE_LEN);
+
+        let lines = output_queue.privmsg_lines("#chan", &"a".repeat(budget + 1));
+        assert_eq!(lines.len(), 2);
+        // Between words when possible
+        let text = format!("{} {}", "a".repeat(budget - 2), "b".repeat(5));
+        let lines = output_queue.privmsg_lines("#chan", &text);
+        assert_eq!(lines[1], "PRIVMSG #chan :bbbbb\n");
+    }
+
+    #[test]
+    fn prefix_longer_than_a_line() {
+        let output_queue = output_queue("long-prefix");
+        output_queue.irc_session.set_registered("n".repeat(600));
+        assert_eq!(output_queue.budget("#chan"), 0);
+        // At least one char per line
+        let lines = output_queue.privmsg_lines("#chan", "ééé");
+        assert_eq!(lines, vec!["PRIVMSG #chan :éé\n", "PRIVMSG #chan :é\n"]);
+    }
+
+    #[test]
+    fn queue_order_and_reply_cap() {
+        let output_queue = output_queue("order");
+        output_queue.privmsg("#chan", "news");
+        output_queue.send_urgent("PONG :server\n".to_string());
+        let texts: Vec<String> = (1..=5).map(|i| format!("reply {i}")).collect();
+        output_queue.reply("op", &texts);
+        // No room left for this one
+        output_queue.reply("op", &["another reply".to_string()]);
+
+        let mut lines = vec![];
+        while let Some(line) = output_queue.pop() {
+            output_queue.inner.0.lock().unwrap().writing = false;
+            lines.push(line);
+        }
+        assert_eq!(
+            lines,
+            vec![
+                "PONG :server\n",
+                "PRIVMSG #chan :news\n",
+                "PRIVMSG op :reply 1\n",
+                "PRIVMSG op :reply 2\n",
+                "PRIVMSG op :... 3 line(s) dropped\n",
+            ]
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// What we know about the current IRC connection. Everything in here is reset when we register
+// again (after a reconnection for example)
+#[derive(Default)]
+struct Session {
+    // Capabilities advertised by the server (CAP LS), values included (ex: "sasl=PLAIN,EXTERNAL")
+    caps: Vec<String>,
+    authenticated: bool,
+    // The nick we have (or asked for, until we are registered)
+    nick: String,
+    // Number of fallback nicks tried during the registration
+    nick_attempts: usize,
+    registered: bool,
+    last_regain: Option<Instant>,
+    // Our user name and host, as the server shows them to the others (learnt when we join)
+    user: Option<String>,
+    host: Option<String>,
+}
+
+// Used until we know better (USERLEN is often 10, plus the '~' of a missing ident, and host
+// names are at most 63 bytes long)
+const MAX_USER_LEN: usize = 11;
+const MAX_HOST_LEN: usize = 63;
+// Every server accepts nicks that long (RFC 2812), the fallback nicks are never longer
+const MAX_NICK_LEN: usize = 9;
+
+// The following structure allows sharing the session between multiple threads
+#[derive(Clone, Default)]
+pub struct IrcSession {
+    inner: Arc<Mutex<Session>>,
+}
+
+impl IrcSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn reset(&self, nick: String) {
+        *self.inner.lock().expect("Poisoned lock!") = Session {
+            nick,
+            ..Session::default()
+        };
+    }
+    pub fn add_caps(&self, caps: &str) {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .caps
+            .extend(caps.split_whitespace().map(ToString::to_string));
+    }
+    // Returns the value of a capability (empty if it has none), or None if the server
+    // doesn't support it
+    pub fn cap(&self, name: &str) -> Option<String> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .caps
+            .iter()
+            .find_map(|cap| match cap.split_once('=') {
+                Some((cap_name, value)) if cap_name == name => Some(value.to_string()),
+                None if cap == name => Some(String::new()),
+                _ => None,
+            })
+    }
+    pub fn set_authenticated(&self) {
+        self.inner.lock().expect("Poisoned lock!").authenticated = true;
+    }
+    pub fn is_authenticated(&self) -> bool {
+        self.inner.lock().expect("Poisoned lock!").authenticated
+    }
+    pub fn nick(&self) -> String {
+        self.inner.lock().expect("Poisoned lock!").nick.clone()
+    }
+    pub fn set_nick(&self, nick: String) {
+        self.inner.lock().expect("Poisoned lock!").nick = nick;
+    }
+    // Returns the nick to try when the current one is taken or refused : the alternate nicks
+    // first, then 'nick' followed by a number (nick1, nick2...), cut to fit in MAX_NICK_LEN
+    pub fn next_nick(&self, nick: &str, alt_nicks: &[String]) -> String {
+        let mut session = self.inner.lock().expect("Poisoned lock!");
+        session.nick = alt_nicks
+            .get(session.nick_attempts)
+            .cloned()
+            .unwrap_or_else(|| {
+                let number = (session.nick_attempts - alt_nicks.len() + 1).to_string();
+                let base =
+                    crate::irc_output::truncate(nick, MAX_NICK_LEN.saturating_sub(number.len()));
+                format!("{base}{number}")
+            });
+        session.nick_attempts += 1;
+        session.nick.clone()
+    }
+    pub fn set_registered(&self, nick: String) {
+        let mut session = self.inner.lock().expect("Poisoned lock!");
+        session.registered = true;
+        session.nick = nick;
+    }
+    pub fn is_registered(&self) -> bool {
+        self.inner.lock().expect("Poisoned lock!").registered
+    }
+    pub fn set_user_host(&self, user: String, host: String) {
+        let mut session = self.inner.lock().expect("Poisoned lock!");
+        session.user = Some(user);
+        session.host = Some(host);
+    }
+    pub fn set_host(&self, host: String) {
+        self.inner.lock().expect("Poisoned lock!").host = Some(host);
+    }
+    // Length of the prefix the server adds to the messages we send (":nick!user@host ")
+    pub fn prefix_len(&self) -> usize {
+        let session = self.inner.lock().expect("Poisoned lock!");
+        session.nick.len()
+            + session.user.as_ref().map_or(MAX_USER_LEN, String::len)
+            + session.host.as_ref().map_or(MAX_HOST_LEN, String::len)
+            + ":! @".len()
+    }
+    // Returns true (and starts a new period) if we didn't try to regain our nick for 'delay'
+    pub fn regain_due(&self, delay: Duration) -> bool {
+        let mut session = self.inner.lock().expect("Poisoned lock!");
+        if session.last_regain.is_some_and(|t| t.elapsed() < delay) {
+            return false;
+        }
+        session.last_regain = Some(Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback_nicks() {
+        let irc_session = IrcSession::new();
+        irc_session.reset("gruik".to_string());
+        let alt_nicks = vec!["gruik_".to_string()];
+        let nicks: Vec<String> = (0..3)
+            .map(|_| irc_session.next_nick("gruik", &alt_nicks))
+            .collect();
+        assert_eq!(nicks, vec!["gruik_", "gruik1", "gruik2"]);
+
+        irc_session.reset("averylongnick".to_string());
+        let nicks: Vec<String> = (0..11)
+            .map(|_| irc_session.next_nick("averylongnick", &[]))
+            .collect();
+        assert_eq!(nicks[0], "averylon1");
+        assert_eq!(nicks[10], "averylo11");
+        assert!(nicks.iter().all(|nick| nick.len() <= MAX_NICK_LEN));
+    }
+}
@@ -1,163 +1,456 @@
+mod feed_discovery;
+mod feed_map;
+mod feed_status;
 mod gruik_config;
+mod html_text;
+mod http_fetch;
+mod http_server;
+mod irc_connection;
+mod irc_output;
+mod irc_session;
+mod news_dedup;
+mod news_filter;
+mod news_search;
+mod news_store;
+mod opml;
+mod shutdown;
+mod template;
+mod yaml_edit;
 
 use chrono::{DateTime, Utc};
-use gruik_config::GruikConfig;
+use feed_status::FeedsStatus;
+use gruik_config::{ConfigChanges, Duplicates, Excerpt, Feed, GruikConfig, StoreBackend};
+use html_text::to_plain_text;
+use irc_output::OutputQueue;
+use irc_session::IrcSession;
 use loirc::Message;
+use news_search::Search;
+use news_store::NewsStore;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 use std::{env, fs, sync::Arc, sync::Mutex, thread};
+use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
 
-use crate::gruik_config::IrcColor;
+use template::ColorKey;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 struct News {
+    // URL of the feed this news comes from
+    #[serde(default)]
+    feed: String,
+    // Channels the news was posted to
+    #[serde(default)]
+    channels: Vec<String>,
+    // Recorded without being posted, because of the filters or as a duplicate
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    filtered: bool,
+    // The id of the item in its feed, if the feed gives one
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    guid: String,
+    // Canonical URL of the main link
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    url: String,
+    // Words of the title, when the feed has 'dedup_title'
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    fingerprint: String,
     origin: String,
     title: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    authors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    categories: Vec<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    summary: String,
     links: Vec<String>,
     date: DateTime<Utc>,
+    // Beginning of news_dedup::news_id(), long enough to be unambiguous
     hash: String,
 }
 
+// The latest news, in front of the store when there is one
+#[derive(Default)]
+struct NewsCache {
+    news: VecDeque<News>,
+    store: Option<NewsStore>,
+    // Nothing is saved before the saved news are loaded
+    loaded: bool,
+}
+
 #[derive(Clone)]
 struct NewsList {
-    inner: Arc<Mutex<VecDeque<News>>>,
+    inner: Arc<Mutex<NewsCache>>,
+}
+
+// News saved without channels were posted to 'default_channel'
+fn read_news_file(feed_file: &str, default_channel: &str) -> Result<VecDeque<News>, String> {
+    let buf = match fs::read_to_string(feed_file) {
+        Ok(r) => r,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => return Err(format!("Can't read {feed_file} : {e}")),
+    };
+    if buf.trim().is_empty() {
+        return Ok(VecDeque::new());
+    }
+    let mut news_list: VecDeque<News> =
+        serde_json::from_str(&buf).map_err(|e| format!("Can't parse {feed_file} : {e}"))?;
+    for news in &mut news_list {
+        if news.channels.is_empty() {
+            news.channels.push(default_channel.to_string());
+        }
+    }
+    Ok(news_list)
 }
 
 impl NewsList {
     fn new() -> Self {
         Self {
-            inner: Arc::new(Mutex::new(VecDeque::new())),
+            inner: Arc::new(Mutex::new(NewsCache::default())),
+        }
+    }
+
+    // Returns true if the news was already posted to this channel
+    fn contains(&self, news: &News, channel: &str) -> bool {
+        let cache = self.inner.lock().expect("Poisoned lock!");
+        if cache
+            .news
+            .iter()
+            .any(|n| n.same_as(news) && n.channels.iter().any(|c| c == channel))
+        {
+            return true;
         }
+        // Older news are only in the store
+        cache.store.as_ref().is_some_and(|store| {
+            store.contains(news, channel).unwrap_or_else(|e| {
+                println!("Can't look for {} in the store : {e}", news.title);
+                false
+            })
+        })
     }
 
-    fn contains(&self, news: &News) -> bool {
-        for n in &*self.inner.lock().expect("Poisoned lock!") {
-            if n.hash == news.hash {
-                return true;
+    /*
+     * Returns the hash of the news : the one it already has if it was posted to another
+     * channel, else the shortest beginning of its id that can't be mistaken for another hash
+     */
+    fn hash_of(&self, news: &News) -> String {
+        let cache = self.inner.lock().expect("Poisoned lock!");
+        if let Some(n) = cache.news.iter().find(|n| n.same_as(news)) {
+            return n.hash.clone();
+        }
+        if let Some(store) = &cache.store {
+            match store.find_hash(news) {
+                Ok(Some(hash)) => return hash,
+                Ok(None) => {}
+                Err(e) => println!("Can't look for {} in the store : {e}", news.title),
             }
         }
-        false
+        let id = news_dedup::news_id(news);
+        let mut len = news_dedup::HASH_LEN;
+        while len < id.len()
+            && (cache
+                .news
+                .iter()
+                .any(|n| news_dedup::ambiguous(&id[..len], &n.hash))
+                || cache
+                    .store
+                    .as_ref()
+                    .is_some_and(|store| store.is_ambiguous(&id[..len]).unwrap_or(false)))
+        {
+            len += 1;
+        }
+        id[..len].to_string()
     }
 
     fn get_all(&self) -> VecDeque<News> {
         // We return a copy of the data in the struct
-        self.inner.lock().expect("Poisoned lock!").clone()
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .news
+            .iter()
+            .filter(|x| !x.filtered)
+            .cloned()
+            .collect()
     }
 
-    fn load_file(&self, feed_file: &String) {
-        let mut f = match fs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(feed_file)
-        {
-            Ok(r) => r,
-            Err(e) => {
-                println!("Can't open {feed_file} : {e}");
-                std::process::exit(1);
-            }
-        };
-        let mut buf = String::new();
-        f.read_to_string(&mut buf).unwrap_or(0);
-        *self.inner.lock().expect("Poisoned lock!") =
-            serde_json::from_str(&buf).unwrap_or_default();
-    }
-
-    fn save_file(&self, feed_file: &String) {
-        let mut f = match fs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(feed_file)
-        {
-            Ok(r) => r,
-            Err(e) => {
-                println!("Can't open {feed_file} : {e}");
-                std::process::exit(1);
-            }
+    /*
+     * Loads the news posted before we started
+     *
+     * With the SQLite store, the news of {irc.channel}-feed.json are moved to the store the
+     * first time (the file is then renamed to {irc.channel}-feed.json.migrated)
+     */
+    fn load(&self, gruik_config: &GruikConfig) {
+        let irc_channel = gruik_config.irc_channel();
+        let feed_file = irc_channel.clone() + "-feed.json";
+        let news_list = match gruik_config.store_backend() {
+            StoreBackend::Json => read_news_file(&feed_file, &irc_channel),
+            StoreBackend::Sqlite => self.open_store(gruik_config, &feed_file),
         };
-        match f.set_len(0) {
-            Ok(()) => {
-                if let Err(e) = f.write_all(
-                    serde_json::to_string(&*self.inner.lock().expect("Poisoned lock!"))
-                        .unwrap_or_default()
-                        .as_bytes(),
-                ) {
-                    println!("Failed to write {feed_file} : {e}");
-                }
+        match news_list {
+            Ok(news_list) => {
+                let mut cache = self.inner.lock().expect("Poisoned lock!");
+                cache.news = news_list;
+                cache.loaded = true;
             }
             Err(e) => {
-                println!("Failed to truncate {feed_file} : {e}");
+                println!("{e}\nexiting.");
+                std::process::exit(1);
             }
         }
     }
-    fn add(&self, news: News, ringsize: usize) {
-        let mut news_list_guarded = self.inner.lock().expect("Poisoned lock!");
 
-        if news_list_guarded.len() > ringsize {
-            news_list_guarded.pop_front();
-        } else {
-            news_list_guarded.push_back(news);
+    // Returns the latest news of the store
+    fn open_store(
+        &self,
+        gruik_config: &GruikConfig,
+        feed_file: &str,
+    ) -> Result<VecDeque<News>, String> {
+        let path = gruik_config.store_path();
+        let mut store = NewsStore::open(&path)?;
+        if store.is_empty()? && std::path::Path::new(feed_file).exists() {
+            let mut news_list = read_news_file(feed_file, &gruik_config.irc_channel())?;
+            let n = store
+                .import(news_list.make_contiguous())
+                .map_err(|e| format!("Can't migrate {feed_file} to {path} : {e}"))?;
+            let migrated = feed_file.to_string() + ".migrated";
+            fs::rename(feed_file, &migrated)
+                .map_err(|e| format!("Can't rename {feed_file} to {migrated} : {e}"))?;
+            println!("{n} news migrated from {feed_file} to {path}");
         }
+        let news_list = store
+            .latest(gruik_config.feeds_ringsize())
+            .map_err(|e| format!("Can't read {path} : {e}"))?;
+        self.inner.lock().expect("Poisoned lock!").store = Some(store);
+        Ok(news_list.into())
     }
 
-    fn get_latest(&self, n: usize, origin: &[&str]) -> Vec<News> {
-        let mut res = Vec::new();
-        let mut n = n;
-        let news_list_guarded = self.inner.lock().expect("Poisoned lock!");
-        if origin.is_empty() {
-            let len = if news_list_guarded.len() > 1 {
-                news_list_guarded.len() - 1
-            } else {
-                0
-            };
-            if n > len {
-                n = len;
+    // The SQLite store is always up to date, we only have to forget the old news
+    fn save(&self, gruik_config: &GruikConfig) {
+        let cache = self.inner.lock().expect("Poisoned lock!");
+        if !cache.loaded {
+            return;
+        }
+        if let Some(store) = &cache.store {
+            match store.prune(gruik_config.store_max_age(), gruik_config.store_max_news()) {
+                Ok(0) => {}
+                Ok(n) => println!("{n} old news removed from the store"),
+                Err(e) => println!("Can't remove the old news from the store : {e}"),
             }
-            for i in 0..n {
-                res.push(
-                    news_list_guarded
-                        .get(len - i)
-                        .expect("Missing news in slice ?!?")
-                        .clone(),
-                );
+            return;
+        }
+        let feed_file = gruik_config.irc_channel() + "-feed.json";
+        let json = serde_json::to_string(&cache.news).unwrap_or_default();
+        if let Err(e) = gruik_config::write_atomically(&feed_file, &json) {
+            println!("Failed to write {feed_file} : {e}");
+        }
+    }
+
+    // Marks the news as posted to this channel
+    fn add(&self, news: News, channel: &str, ringsize: usize) {
+        let mut cache = self.inner.lock().expect("Poisoned lock!");
+
+        if let Some(store) = &cache.store
+            && let Err(e) = store.add(&news, channel)
+        {
+            println!("Can't add {} to the store : {e}", news.hash);
+        }
+        if let Some(n) = cache.news.iter_mut().find(|n| n.hash == news.hash) {
+            if !n.channels.iter().any(|c| c == channel) {
+                n.channels.push(channel.to_string());
             }
-        } else {
-            let origin = origin.join(" ");
-            let show_news: Vec<&News> = news_list_guarded
+            return;
+        }
+        let mut news = news;
+        news.channels = vec![channel.to_string()];
+        cache.news.push_back(news);
+        while cache.news.len() > ringsize {
+            cache.news.pop_front();
+        }
+    }
+
+    // Returns the latest news the given one duplicates (see News::duplicate_of())
+    fn find_duplicate(&self, news: &News, window: chrono::Duration) -> Option<News> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .news
+            .iter()
+            .rev()
+            .find(|n| n.duplicate_of(news, window))
+            .cloned()
+    }
+
+    // Adds an origin to a news, returns the news with its origins
+    fn merge_origin(&self, news: &News, origin: &str) -> News {
+        let mut cache = self.inner.lock().expect("Poisoned lock!");
+        let merged = News {
+            origin: format!("{}, {origin}", news.origin),
+            ..news.clone()
+        };
+        if let Some(store) = &cache.store
+            && let Err(e) = store.set_origin(&news.hash, &merged.origin)
+        {
+            println!("Can't update {} in the store : {e}", news.hash);
+        }
+        for n in cache.news.iter_mut().filter(|n| n.hash == news.hash) {
+            n.origin.clone_from(&merged.origin);
+        }
+        merged
+    }
+
+    // Returns a page of the news found (newest first), and how many were found. Without the
+    // SQLite store, only the latest news can be found
+    fn search(&self, search: &Search) -> Result<(Vec<News>, usize), String> {
+        let cache = self.inner.lock().expect("Poisoned lock!");
+        if let Some(store) = &cache.store {
+            return store.search(search);
+        }
+        let mut found: Vec<&News> = cache.news.iter().filter(|n| search.matches(n)).collect();
+        found.sort_by_key(|n| std::cmp::Reverse(n.date));
+        Ok((
+            found
                 .iter()
-                .filter(|x| *x.origin == origin)
-                .collect();
-            let len = if show_news.len() > 1 {
-                show_news.len() - 1
-            } else {
-                0
+                .skip(search.offset())
+                .take(news_search::PAGE_SIZE)
+                .map(|n| (*n).clone())
+                .collect(),
+            found.len(),
+        ))
+    }
+
+    // Returns the n latest news, newest first, optionally from a channel and/or an origin
+    fn get_latest(&self, n: usize, channel: Option<&str>, origin: &[&str]) -> Vec<News> {
+        let origin = origin.join(" ");
+        self.inner
+            .lock()
+            .expect("Poisoned lock!")
+            .news
+            .iter()
+            .rev()
+            .filter(|x| !x.filtered)
+            .filter(|x| channel.is_none_or(|c| x.channels.iter().any(|xc| xc == c)))
+            .filter(|x| origin.is_empty() || x.has_origin(&origin))
+            .take(n)
+            .cloned()
+            .collect()
+    }
+}
+
+// SASL was asked for and failed : we don't want to stay connected without being authenticated
+fn sasl_abort(irc_writer: &irc_connection::Writer, reason: &str) -> ! {
+    println!("SASL authentication failed : {reason}\nexiting.");
+    let _ = irc_writer.raw("QUIT :SASL authentication failed\n");
+    let _ = irc_writer.close();
+    std::process::exit(1);
+}
+
+/*
+ * IRCv3 capability negotiation (https://ircv3.net/specs/extensions/capability-negotiation)
+ *
+ * The only capability we care about is 'sasl', CAP END is sent as soon as we know we don't
+ * need it, or once the SASL authentication succeeded (903)
+ */
+fn handle_cap(
+    gruik_config: &GruikConfig,
+    irc_writer: &irc_connection::Writer,
+    irc_session: &IrcSession,
+    args: &[String],
+) {
+    let subcommand = args.get(1).map_or("", String::as_str);
+    let caps = args.last().map_or("", String::as_str);
+
+    match subcommand {
+        "LS" => {
+            irc_session.add_caps(caps);
+            // 'CAP * LS * :caps' means that more lines are coming
+            if args.len() > 3 && args[2] == "*" {
+                return;
+            }
+            let Some(mechanism) = gruik_config.irc_sasl() else {
+                if let Err(e) = irc_writer.raw("CAP END\n") {
+                    println!("Couldn't send the 'CAP END' command : {e:?}");
+                }
+                return;
             };
-            if n > len {
-                n = len;
+            match irc_session.cap("sasl") {
+                None => sasl_abort(irc_writer, "the server doesn't support SASL"),
+                // The value (when present) is the list of supported mechanisms
+                Some(mechanisms)
+                    if !mechanisms.is_empty()
+                        && !mechanisms.split(',').any(|m| m == mechanism.to_string()) =>
+                {
+                    sasl_abort(
+                        irc_writer,
+                        &format!("the server doesn't support {mechanism} (only {mechanisms})"),
+                    );
+                }
+                Some(_) => {
+                    if let Err(e) = irc_writer.raw("CAP REQ :sasl\n") {
+                        println!("Couldn't send the 'CAP REQ' command : {e:?}");
+                    }
+                }
             }
-
-            for i in 0..n {
-                res.push(
-                    news_list_guarded
-                        .get(len - i)
-                        .expect("Missing news in slice ?!?")
-                        .clone(),
-                );
+        }
+        "ACK" => {
+            if let Some(mechanism) = gruik_config.irc_sasl()
+                && caps.split_whitespace().any(|c| c == "sasl")
+                && let Err(e) = irc_writer.raw(format!("AUTHENTICATE {mechanism}\n"))
+            {
+                println!("Couldn't send the 'AUTHENTICATE' command : {e:?}");
             }
         }
-        res
+        "NAK" => sasl_abort(irc_writer, &format!("the server refused '{caps}'")),
+        _ => {}
     }
 }
 
+// The server is ready ('AUTHENTICATE +'), we send our credentials
+fn sasl_authenticate(gruik_config: &GruikConfig, irc_writer: &irc_connection::Writer) {
+    use base64::Engine;
+
+    let payload = match gruik_config.irc_sasl() {
+        Some(gruik_config::SaslMechanism::Plain) => {
+            let account = gruik_config.irc_account();
+            let password = gruik_config.irc_password().unwrap_or_default();
+            base64::engine::general_purpose::STANDARD
+                .encode(format!("{account}\0{account}\0{password}"))
+        }
+        // EXTERNAL uses the TLS client certificate, there is nothing to send
+        Some(gruik_config::SaslMechanism::External) | None => String::new(),
+    };
+
+    // The payload is sent in chunks of 400 bytes, an empty line ('+') ends it when its
+    // length is a multiple of 400
+    let chunks: Vec<&str> = payload
+        .as_bytes()
+        .chunks(400)
+        .map(|c| std::str::from_utf8(c).expect("base64 is ASCII"))
+        .collect();
+    for chunk in &chunks {
+        if let Err(e) = irc_writer.raw(format!("AUTHENTICATE {chunk}\n")) {
+            println!("Couldn't send the 'AUTHENTICATE' command : {e:?}");
+        }
+    }
+    if chunks.last().is_none_or(|c| c.len() == 400)
+        && let Err(e) = irc_writer.raw("AUTHENTICATE +\n")
+    {
+        println!("Couldn't send the 'AUTHENTICATE' command : {e:?}");
+    }
+}
+
+// Number of items shown by !testfilter
+const TESTFILTER_ITEMS: usize = 10;
+
 fn handle_irc_messages(
     gruik_config: &GruikConfig,
-    irc_writer: &loirc::Writer,
+    irc_writer: &irc_connection::Writer,
+    irc_session: &IrcSession,
+    output_queue: &OutputQueue,
     msg: Message,
     news_list: &NewsList,
+    feeds_status: &FeedsStatus,
 ) {
     use loirc::Prefix::{Server, User};
 
@@ -168,15 +461,94 @@ fn handle_irc_messages(
      * PING
      */
     if msg.code == loirc::Code::Ping {
-        let ping_arg = msg.args.get(0).map_or_else(
-            || {
-                println!("Can't get ping argument! exiting.");
-                std::process::exit(1);
-            },
-            |s| s,
-        );
-        if let Err(e) = irc_writer.raw(format!("PONG :{ping_arg}\n")) {
-            println!("Couldn't send the 'PONG' command{e:?}");
+        let ping_arg = msg.args.first().unwrap_or_else(|| {
+            println!("Can't get ping argument! exiting.");
+            std::process::exit(1);
+        });
+        output_queue.send_urgent(format!("PONG :{ping_arg}\n"));
+        return;
+    }
+    /*
+     * CAP
+     */
+    if let loirc::Code::Unknown(ref command) = msg.code
+        && command == "CAP"
+    {
+        handle_cap(gruik_config, irc_writer, irc_session, &msg.args);
+        return;
+    }
+    /*
+     * AUTHENTICATE
+     */
+    if let loirc::Code::Unknown(ref command) = msg.code
+        && command == "AUTHENTICATE"
+    {
+        if msg.args.first().is_some_and(|arg| arg == "+") {
+            sasl_authenticate(gruik_config, irc_writer);
+        }
+        return;
+    }
+    /*
+     * SASL numerics (https://ircv3.net/specs/extensions/sasl-3.1)
+     */
+    if let loirc::Code::Unknown(ref numeric) = msg.code {
+        let text = msg.args.last().map_or("", String::as_str);
+        match numeric.as_str() {
+            // RPL_LOGGEDIN
+            "900" => println!("{text}"),
+            // RPL_SASLSUCCESS
+            "903" => {
+                irc_session.set_authenticated();
+                if let Err(e) = irc_writer.raw("CAP END\n") {
+                    println!("Couldn't send the 'CAP END' command : {e:?}");
+                }
+            }
+            // ERR_NICKLOCKED, ERR_SASLFAIL, ERR_SASLTOOLONG, ERR_SASLABORTED
+            "902" | "904" | "905" | "906" => sasl_abort(irc_writer, text),
+            // RPL_HOSTHIDDEN : our host is now hidden behind the one given
+            "396" => {
+                if let Some(host) = msg.args.get(1) {
+                    irc_session.set_host(host.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    /*
+     * ERR_NICKNAMEINUSE / ERR_UNAVAILRESOURCE / ERR_ERRONEOUSNICKNAME (too long for example)
+     */
+    if msg.code == loirc::Code::ErrNicknameinuse
+        || msg.code == loirc::Code::ErrUnavailresource
+        || msg.code == loirc::Code::ErrErroneousnickname
+    {
+        // Once registered, this is the answer to a regain attempt : we keep our current nick
+        if irc_session.is_registered() {
+            return;
+        }
+        let taken = msg.args.get(1).map_or("", String::as_str);
+        let nick = irc_session.next_nick(&gruik_config.irc_nick(), &gruik_config.irc_alt_nicks());
+        println!("{taken} is not available, trying {nick}");
+        if let Err(e) = irc_writer.raw(format!("NICK {nick}\n")) {
+            println!("Couldn't send the 'NICK' command : {e:?}");
+        }
+        return;
+    }
+    /*
+     * NICK / QUIT
+     */
+    if msg.code == loirc::Code::Nick || msg.code == loirc::Code::Quit {
+        let Some(User(user)) = &msg.prefix else {
+            return;
+        };
+        let irc_nick = gruik_config.irc_nick();
+        if user.nickname == irc_session.nick() {
+            if let Some(nick) = msg.args.first().filter(|_| msg.code == loirc::Code::Nick) {
+                println!("Our nick is now {nick}");
+                irc_session.set_nick(nick.clone());
+            }
+        } else if user.nickname == irc_nick {
+            // Our nick was just freed, we take it back
+            output_queue.send_urgent(format!("NICK {irc_nick}\n"));
         }
         return;
     }
@@ -184,13 +556,30 @@ fn handle_irc_messages(
      * RPL_WELCOME
      */
     if msg.code == loirc::Code::RplWelcome {
-        if let Err(e) = irc_writer.raw(format!("JOIN {irc_channel}\n")) {
-            println!("Couldn't join {irc_channel} : {e:?}");
+        // The server doesn't know about CAP, or skipped the SASL authentication
+        if gruik_config.irc_sasl().is_some() && !irc_session.is_authenticated() {
+            sasl_abort(irc_writer, "registration completed without authentication");
         }
-        for channel in xchannels {
-            if let Err(e) = irc_writer.raw(format!("JOIN {channel}\n")) {
-                println!("Couldn't join {channel} : {e:?}");
-            }
+        // The first argument is the nick the server knows us by
+        irc_session.set_registered(
+            msg.args
+                .first()
+                .map_or_else(|| irc_session.nick(), Clone::clone),
+        );
+        for channel in gruik_config.channels() {
+            output_queue.send_urgent(format!("JOIN {channel}\n"));
+        }
+        return;
+    }
+    /*
+     * JOIN
+     */
+    if msg.code == loirc::Code::Join {
+        // Our own JOIN tells us how the server shows us to the others
+        if let Some(User(user)) = &msg.prefix
+            && user.nickname == irc_session.nick()
+        {
+            irc_session.set_user_host(user.username.clone(), user.hostname.clone());
         }
         return;
     }
@@ -211,13 +600,50 @@ fn handle_irc_messages(
          * !lsfeeds
          */
         if msg_str.starts_with("!lsfeeds") {
-            for (i, feed) in gruik_config.feeds_urls().iter().enumerate() {
-                if let Err(e) = irc_writer.raw(format!("PRIVMSG {} {}. {feed}\n", &msg_source, i)) {
-                    println!("Failed to send an IRC message... ({e:?})");
-                } else {
-                    thread::sleep(gruik_config.irc_delay());
+            // !lsfeeds [#channel] : the indexes are the ones expected by !rmfeed
+            let channel = msg_args.first().filter(|c| c.starts_with(['#', '&']));
+            let mut lines = Vec::new();
+            for (i, (feed, description)) in gruik_config
+                .feeds()
+                .iter()
+                .zip(gruik_config.feeds_descriptions())
+                .enumerate()
+            {
+                if channel.is_some_and(|c| !feed.channels.iter().any(|fc| fc == c)) {
+                    continue;
                 }
+                lines.push(format!("{i}. {description}"));
+            }
+            output_queue.reply(&msg_source, &lines);
+        }
+        /*
+         * !feedstatus
+         */
+        else if msg_str.starts_with("!feedstatus") {
+            let mut lines = Vec::new();
+            for (i, feed) in gruik_config.feeds().iter().enumerate() {
+                let status = feeds_status.get(&feed.url);
+                let last_error = status.last_error.unwrap_or_default();
+                let text = if !feed.enabled {
+                    "disabled in the configuration".to_string()
+                } else if status.disabled {
+                    format!(
+                        "disabled after {} failures, last error : {last_error}",
+                        status.failures
+                    )
+                } else if status.failures > 0 {
+                    format!(
+                        "failing ({} failures), last error : {last_error}",
+                        status.failures
+                    )
+                } else if let Some(last_success) = status.last_success {
+                    format!("ok, last success {}", last_success.format("%F %T"))
+                } else {
+                    "not fetched yet".to_string()
+                };
+                lines.push(format!("{i}. {} : {text}", feed.url));
             }
+            output_queue.reply(&msg_source, &lines);
         }
         /*
          * !xpost
@@ -230,32 +656,55 @@ fn handle_irc_messages(
                 println!("{}", news.hash);
                 if news.hash == hash {
                     for channel in &xchannels {
-                        if let Err(e) = irc_writer.raw(format!(
-                            "PRIVMSG {} {} (from {msg_source} on {irc_channel})\n",
-                            &channel,
-                            fmt_news(gruik_config, &news),
-                        )) {
-                            println!("Failed to send an IRC message... ({e:?})");
-                        } else {
-                            thread::sleep(gruik_config.irc_delay());
-                        }
+                        let from = format!(" (from {msg_source} on {irc_channel})");
+                        let budget = output_queue.budget(channel).saturating_sub(from.len());
+                        output_queue.privmsg(
+                            channel,
+                            &(fmt_news(gruik_config, &news, Some(channel), budget) + &from),
+                        );
                     }
                 }
             }
         }
+        /*
+         * !search
+         */
+        else if msg_str.starts_with("!search") {
+            let args = msg_str.strip_prefix("!search").unwrap_or_default();
+            let found = Search::parse(args)
+                .and_then(|search| news_list.search(&search).map(|found| (search, found)));
+            let lines = match found {
+                Ok((search, (found, total))) => {
+                    let pages = total.div_ceil(news_search::PAGE_SIZE).max(1);
+                    let mut lines = vec![if search.page > pages {
+                        format!("{total} result(s), there is no page {}", search.page)
+                    } else {
+                        format!("{total} result(s), page {}/{pages}", search.page)
+                    }];
+                    lines.extend(found.iter().map(|news| {
+                        fmt_news(gruik_config, news, None, output_queue.budget(&msg_source))
+                    }));
+                    if search.page < pages {
+                        lines.push(format!("add --page {} for more", search.page + 1));
+                    }
+                    lines
+                }
+                Err(e) => vec![format!(
+                    "usage: !search <terms> [--from origin] [--since 3d] [--until 1d] \
+                     [--page n] ({e})"
+                )],
+            };
+            output_queue.reply(&msg_source, &lines);
+        }
         /*
          * !latest
          */
         else if msg_str.starts_with("!latest") {
             if msg_args.is_empty() {
-                if let Err(e) = irc_writer.raw(format!(
-                    "PRIVMSG {} {}\n",
-                    msg_source, "usage: !latest <number> [origin]"
-                )) {
-                    println!("Failed to send an IRC message... ({e:?})");
-                } else {
-                    thread::sleep(gruik_config.irc_delay());
-                }
+                output_queue.reply(
+                    &msg_source,
+                    &["usage: !latest <number> [#channel] [origin]".to_string()],
+                );
                 return;
             }
 
@@ -264,33 +713,29 @@ fn handle_irc_messages(
                 None => 0,
                 Some(arg) => match arg.parse() {
                     Err(_) => {
-                        if let Err(e) = irc_writer.raw(format!(
-                            "PRIVMSG {} {}\n",
-                            msg_source, "!latest : conversion error"
-                        )) {
-                            println!("Failed to send an IRC message... ({e:?})");
-                        } else {
-                            thread::sleep(gruik_config.irc_delay());
-                        }
+                        output_queue
+                            .reply(&msg_source, &["!latest : conversion error".to_string()]);
                         return;
                     }
                     Ok(n) => n,
                 },
             };
 
-            let origin: &[&str] = msg_args.get(1..).map_or(&[], |v| v);
-
-            for news in news_list.get_latest(n, origin) {
-                if let Err(e) = irc_writer.raw(format!(
-                    "PRIVMSG {} {}\n",
-                    msg_source,
-                    fmt_news(gruik_config, &news)
-                )) {
-                    println!("Failed to send an IRC message... ({e:?})");
-                } else {
-                    thread::sleep(gruik_config.irc_delay());
+            let mut origin: &[&str] = msg_args.get(1..).map_or(&[], |v| v);
+            let channel = match origin.first() {
+                Some(c) if c.starts_with(['#', '&']) => {
+                    origin = &origin[1..];
+                    Some(*c)
                 }
-            }
+                _ => None,
+            };
+
+            let lines: Vec<String> = news_list
+                .get_latest(n, channel, origin)
+                .iter()
+                .map(|news| fmt_news(gruik_config, news, None, output_queue.budget(&msg_source)))
+                .collect();
+            output_queue.reply(&msg_source, &lines);
 
             return;
         }
@@ -301,29 +746,142 @@ fn handle_irc_messages(
         }
 
         /*
-         * !die
+         * !die [quit message]
          */
         if msg_str.starts_with("!die") {
-            irc_writer
-                .disconnect()
-                .expect("Disconnect should not fail!");
-            std::process::exit(0);
+            let message = msg_str
+                .strip_prefix("!die")
+                .map(str::trim)
+                .filter(|m| !m.is_empty())
+                .map(ToString::to_string);
+            shutdown::die(&msg_source, message);
         }
         /*
          * !addfeed
          */
         else if msg_str.starts_with("!addfeed") {
-            let url = match msg_args.first() {
-                Some(url) => (*url).to_string(),
-                None => return,
+            let Some(url) = msg_args.first().map(ToString::to_string) else {
+                return;
             };
+            // Everything after the url is a list of 'key=value' settings
+            let options: Vec<String> = msg_args
+                .get(1..)
+                .unwrap_or_default()
+                .iter()
+                .map(ToString::to_string)
+                .collect();
 
-            gruik_config.addfeed(url);
+            // Fetching the feed can take a while, we don't want to block the IRC connection
+            let gruik_config = gruik_config.clone();
+            let irc_writer = irc_writer.clone();
+            let output_queue = output_queue.clone();
+            thread::spawn(move || {
+                // TODO : use color in the following message
+                let reply = |msg: &str| output_queue.reply(&msg_source, &[msg.to_string()]);
 
-            // TODO : use color in the following message
-            if let Err(e) = irc_writer.raw(format!("PRIVMSG {msg_source} feed added\n")) {
-                println!("Failed to send an IRC message... ({e:?})");
-            }
+                let feed = match feed_discovery::discover(&url, gruik_config.feeds_timeout()) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        reply(&format!("feed not added : {e}"));
+                        return;
+                    }
+                };
+                reply(&format!(
+                    "found '{}' ({} items) at {}",
+                    feed.title, feed.items, feed.url
+                ));
+
+                let options: Vec<&str> = options.iter().map(String::as_str).collect();
+                match gruik_config.addfeed(feed.url, &options) {
+                    Ok(changes) => {
+                        apply_config_changes(&gruik_config, &irc_writer, &output_queue, &changes);
+                        reply("feed added");
+                    }
+                    Err(e) => reply(&format!("feed not added : {e}")),
+                }
+            });
+        }
+        /*
+         * !enablefeed
+         */
+        else if msg_str.starts_with("!enablefeed") {
+            // This will enable again a feed disabled after too many failures, based on its index
+            let msg = match msg_args.first().unwrap_or(&"").parse::<usize>() {
+                Ok(index) => match gruik_config.feeds().get(index) {
+                    Some(feed) => {
+                        feeds_status.enable(&feed.url);
+                        "feed enabled".to_string()
+                    }
+                    None => "bad index number".to_string(),
+                },
+                Err(e) => format!("index conversion failed ({e})"),
+            };
+            output_queue.reply(&msg_source, &[msg]);
+        }
+        /*
+         * !testfilter
+         */
+        else if msg_str.starts_with("!testfilter") {
+            // The feed is given by its index or its url
+            let arg = msg_args.first().unwrap_or(&"");
+            let feeds = gruik_config.feeds();
+            let feed = arg
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| feeds.get(index))
+                .or_else(|| feeds.iter().find(|feed| feed.url == *arg))
+                .cloned();
+            let Some(feed) = feed else {
+                output_queue.reply(&msg_source, &["usage: !testfilter <index|url>".to_string()]);
+                return;
+            };
+
+            let gruik_config = gruik_config.clone();
+            let output_queue = output_queue.clone();
+            thread::spawn(move || {
+                let http_cache = http_fetch::HttpCache::new();
+                let lines = match fetch_feed(&feed.url, &http_cache, gruik_config.feeds_timeout()) {
+                    (Ok(Some(rss)), _) => rss
+                        .entries
+                        .iter()
+                        .take(TESTFILTER_ITEMS)
+                        .map(|item| {
+                            format!(
+                                "{} : {}",
+                                if feed.filters.accepts(item) {
+                                    "pass"
+                                } else {
+                                    "filtered"
+                                },
+                                item.title.as_ref().map_or_else(
+                                    || "Unknown".to_string(),
+                                    |t| { to_plain_text(&t.content) }
+                                )
+                            )
+                        })
+                        .collect(),
+                    (Ok(None), _) => vec!["the feed didn't change".to_string()],
+                    (Err(e), _) => vec![e],
+                };
+                output_queue.reply(&msg_source, &lines);
+            });
+        }
+        /*
+         * !exportopml
+         */
+        else if msg_str.starts_with("!exportopml") {
+            // The file is written next to the configuration file
+            let opml_file = std::path::Path::new(&gruik_config.filename).with_extension("opml");
+            let opml_file = opml_file.to_string_lossy();
+            let msg = match gruik_config::write_atomically(&opml_file, &opml::export(gruik_config))
+            {
+                Ok(()) => format!(
+                    "{} feed(s) exported to {opml_file}",
+                    gruik_config.feeds().len()
+                ),
+                Err(e) => e,
+            };
+            output_queue.reply(&msg_source, &[msg]);
         }
         /*
          * !rmfeed
@@ -333,23 +891,20 @@ fn handle_irc_messages(
             let index: usize = match msg_args.first().unwrap_or(&"").parse() {
                 Ok(r) => r,
                 Err(e) => {
-                    if let Err(e) = irc_writer.raw(format!(
-                        "PRIVMSG {msg_source} index conversion failed ({e})\n"
-                    )) {
-                        println!("Failed to send an IRC message... ({e:?})");
-                    }
+                    output_queue.reply(&msg_source, &[format!("index conversion failed ({e})")]);
                     return;
                 }
             };
             let msg = match gruik_config.rmfeed(index) {
-                Ok(()) => "feed removed".to_string(),
+                Ok(changes) => {
+                    apply_config_changes(gruik_config, irc_writer, output_queue, &changes);
+                    "feed removed".to_string()
+                }
                 Err(e) => e,
             };
 
             // TODO : use color in the following message
-            if let Err(e) = irc_writer.raw(format!("PRIVMSG {msg_source} {msg}\n")) {
-                println!("Failed to send an IRC message... ({e:?})");
-            }
+            output_queue.reply(&msg_source, &[msg]);
         }
 
         // We discard all other messages
@@ -358,159 +913,636 @@ fn handle_irc_messages(
 
 fn handle_irc_events(
     gruik_config: &GruikConfig,
-    irc_writer: &loirc::Writer,
-    irc_reader: &loirc::Reader,
+    irc_writer: &irc_connection::Writer,
+    irc_reader: &irc_connection::Reader,
+    irc_session: &IrcSession,
+    output_queue: &OutputQueue,
     news_list: &NewsList,
+    feeds_status: &FeedsStatus,
 ) {
-    for event in irc_reader {
+    use std::sync::mpsc::RecvTimeoutError;
+
+    loop {
+        // Rate-limited by irc.regain_delay, whether the server is busy or quiet
+        regain_nick(gruik_config, irc_session, output_queue);
+        // We wake up regularly, even when the server has nothing to say
+        let event = match irc_reader.recv_timeout(std::time::Duration::from_secs(1)) {
+            Ok(r) => r,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
         if gruik_config.debug() {
             dbg!(&event);
         }
-        if let loirc::Event::Message(msg) = event {
-            handle_irc_messages(gruik_config, irc_writer, msg, news_list);
-        } else {
-            println!("Don't know what to do with the following event :");
-            dbg!(event);
+        match event {
+            loirc::Event::Message(msg) => {
+                handle_irc_messages(
+                    gruik_config,
+                    irc_writer,
+                    irc_session,
+                    output_queue,
+                    msg,
+                    news_list,
+                    feeds_status,
+                );
+            }
+            // The server forgot about us, we have to register again
+            loirc::Event::Reconnected => {
+                if let Err(e) = register(gruik_config, irc_writer, irc_session) {
+                    println!("{e}");
+                }
+            }
+            event => {
+                println!("Don't know what to do with the following event :");
+                dbg!(event);
+            }
         }
     }
 }
 
-fn mk_hash(links: &[String]) -> String {
-    use sha2::{Digest, Sha256};
-    base16ct::lower::encode_string(&Sha256::digest(links.join("")))[..8].to_string()
+// We didn't get our nick at registration time, so we regularly try to get it back
+fn regain_nick(gruik_config: &GruikConfig, irc_session: &IrcSession, output_queue: &OutputQueue) {
+    let irc_nick = gruik_config.irc_nick();
+    if !irc_session.is_registered()
+        || irc_session.nick() == irc_nick
+        || !irc_session.regain_due(gruik_config.irc_regain_delay())
+    {
+        return;
+    }
+
+    // NickServ can kick out whoever is using our nick (a stale session of ours for example)
+    if let Some(password) = gruik_config.irc_password() {
+        let command = gruik_config.irc_nickserv();
+        println!("Asking NickServ to {command} {irc_nick}");
+        output_queue.send_urgent(format!(
+            "PRIVMSG NickServ :{command} {irc_nick} {password}\n"
+        ));
+    }
+    // REGAIN changes our nick by itself, GHOST (or no NickServ at all) doesn't
+    output_queue.send_urgent(format!("NICK {irc_nick}\n"));
+}
+
+fn register(
+    gruik_config: &GruikConfig,
+    irc_writer: &irc_connection::Writer,
+    irc_session: &IrcSession,
+) -> Result<(), String> {
+    let irc_nick = gruik_config.irc_nick();
+    irc_session.reset(irc_nick.clone());
+    // The registration is suspended until we send 'CAP END'
+    irc_writer
+        .raw("CAP LS 302\n")
+        .map_err(|e| format!("Can't send the 'CAP' command : {e:?}"))?;
+    irc_writer
+        .raw(format!("NICK {irc_nick}\n"))
+        .map_err(|e| format!("Can't send the 'NICK' command : {e:?}"))?;
+    irc_writer
+        .raw(format!("USER {irc_nick} 0 * :{irc_nick}\n"))
+        .map_err(|e| format!("Can't send the 'USER' command : {e:?}"))
 }
 
-fn fmt_news(gruik_config: &GruikConfig, news: &News) -> String {
-    format!(
-        "[{}{}{}] {}{}{} {}{}{} {}#{}{}",
-        gruik_config.origin_color(),
-        news.origin,
-        IrcColor::Reset,
-        gruik_config.title_color(),
-        news.title,
-        IrcColor::Reset,
-        gruik_config.link_color(),
-        news.links
-            .first()
-            .expect("At least one link should be present!"),
-        IrcColor::Reset,
-        gruik_config.hash_color(),
-        news.hash,
-        IrcColor::Reset
+// 'channel' is None for a private message. The message is shortened to 'max_len' bytes
+fn fmt_news(
+    gruik_config: &GruikConfig,
+    news: &News,
+    channel: Option<&str>,
+    max_len: usize,
+) -> String {
+    // A feed can have its own color for the origin
+    let origin_color = gruik_config
+        .feed(&news.feed)
+        .and_then(|feed| feed.color)
+        .unwrap_or_else(|| gruik_config.origin_color());
+    gruik_config.template(&news.feed, channel).render(
+        news,
+        |key| match key {
+            ColorKey::Origin => origin_color.clone(),
+            ColorKey::Title => gruik_config.title_color(),
+            ColorKey::Link => gruik_config.link_color(),
+            ColorKey::Hash => gruik_config.hash_color(),
+            ColorKey::Excerpt => gruik_config.excerpt_color(),
+        },
+        max_len,
     )
 }
 
+// The excerpt of a news, at most 'max_len' bytes long (None if there is nothing to show)
+fn fmt_excerpt(
+    gruik_config: &GruikConfig,
+    news: &News,
+    feed_config: &Feed,
+    max_len: usize,
+) -> Option<String> {
+    let color = gruik_config.excerpt_color().to_string();
+    let reset = gruik_config::IrcColor::Reset.to_string();
+    let room = max_len.saturating_sub(color.len() + reset.len());
+    let excerpt = irc_output::ellipsize(&news.summary, feed_config.excerpt_length.min(room));
+    (!excerpt.is_empty()).then(|| format!("{color}{excerpt}{reset}"))
+}
+
 /*
- * This function runs in its own thread
+ * Fetch a feed (blocking)
  *
+ * Returns the feed (None if it didn't change since the last fetch), and how long the server
+ * wants us to wait before fetching it again
+ */
+fn fetch_feed(
+    url: &str,
+    http_cache: &http_fetch::HttpCache,
+    timeout: Duration,
+) -> (
+    Result<Option<feed_rs::model::Feed>, String>,
+    Option<Duration>,
+) {
+    println!("Fetching {url}");
+    let response = match http_fetch::fetch(url, http_cache, timeout) {
+        Ok(r) => r,
+        Err(e) => return (Err(e.reason), e.wait),
+    };
+    let Some(body) = response.body else {
+        return (Ok(None), response.wait);
+    };
+    // Without a GUID, the id of an item stays empty (see news_dedup)
+    let parser = feed_rs::parser::Builder::new()
+        .id_generator(|_, _, _| String::new())
+        .build();
+    match parser.parse(body.as_slice()) {
+        Ok(feed) => {
+            http_cache.set(url, response.validators);
+            (Ok(Some(feed)), response.wait)
+        }
+        Err(e) => (Err(format!("Failed to parse feed : {e:?}")), response.wait),
+    }
+}
+
+// Queues the news of a feed that were not posted yet
+fn post_news(
+    gruik_config: &GruikConfig,
+    news_list: &NewsList,
+    output_queue: &OutputQueue,
+    feed_config: &Feed,
+    feed: feed_rs::model::Feed,
+) {
+    let mut i = 0;
+    for item in feed.entries {
+        let origin = feed_config.name.clone().unwrap_or_else(|| {
+            feed.title
+                .as_ref()
+                .map_or_else(|| "Unknown".to_string(), |s| to_plain_text(&s.content))
+        });
+        let date = item.published.unwrap_or_else(Utc::now);
+        let title = item
+            .title
+            .as_ref()
+            .map_or("Unknown".to_string(), |v| to_plain_text(&v.content));
+        let mut links = vec![];
+        for link in &item.links {
+            let href = html_text::clean_link(&link.href);
+            if !href.is_empty() {
+                links.push(href);
+            }
+        }
+        let mut news = News {
+            feed: feed_config.url.clone(),
+            channels: vec![],
+            filtered: false,
+            guid: item.id.trim().to_string(),
+            url: news_dedup::primary_link(&item.links).map_or_else(String::new, |link| {
+                news_dedup::canonical_url(&html_text::clean_link(&link.href))
+            }),
+            fingerprint: if feed_config.dedup_title {
+                news_dedup::title_fingerprint(&title)
+            } else {
+                String::new()
+            },
+            origin,
+            date,
+            title,
+            authors: item
+                .authors
+                .iter()
+                .map(|a| to_plain_text(news_filter::author_name(a)))
+                .collect(),
+            categories: item
+                .categories
+                .iter()
+                .map(|c| to_plain_text(c.label.as_ref().unwrap_or(&c.term)))
+                .collect(),
+            // The summary, or the beginning of the content
+            summary: item
+                .summary
+                .as_ref()
+                .map(|s| to_plain_text(&s.content))
+                .filter(|s| !s.is_empty())
+                .or_else(|| {
+                    item.content
+                        .as_ref()
+                        .and_then(|c| c.body.as_deref())
+                        .map(html_text::first_paragraph)
+                })
+                .unwrap_or_default(),
+            hash: String::new(),
+            links,
+        };
+        news.hash = news_list.hash_of(&news);
+        // Channels where the item wasn't posted yet
+        let mut channels: Vec<&String> = feed_config
+            .channels
+            .iter()
+            .filter(|c| !news_list.contains(&news, c))
+            .collect();
+        // The same story, already posted from another feed
+        let duplicate = (feed_config.duplicates != Duplicates::Off)
+            .then(|| news_list.find_duplicate(&news, feed_config.duplicates_window))
+            .flatten();
+        // A news with the same link is already posted, but it can still get our origin
+        let merge = duplicate.as_ref().filter(|duplicate| {
+            feed_config.duplicates == Duplicates::Merge && !duplicate.has_origin(&news.origin)
+        });
+        if channels.is_empty() && merge.is_none() {
+            println!("already posted {} ({})", news.title, news.hash);
+            continue;
+        }
+        // don't paste news older than feeds.maxage
+        if Utc::now() - news.date > feed_config.maxage {
+            println!("news too old {}", news.date);
+            continue;
+        }
+        // Filtered items are recorded, so that we don't check them again
+        if !feed_config.filters.accepts(&item) {
+            println!("filtered {} ({})", news.title, news.hash);
+            for channel in channels {
+                let news = News {
+                    filtered: true,
+                    ..news.clone()
+                };
+                news_list.add(news, channel, gruik_config.feeds_ringsize());
+            }
+            continue;
+        }
+        // Duplicates are recorded without being posted
+        if let Some(duplicate) = &duplicate {
+            let duplicate_channels: Vec<&String> = feed_config
+                .channels
+                .iter()
+                .filter(|c| duplicate.channels.contains(c))
+                .collect();
+            if !duplicate_channels.is_empty() {
+                if let Some(merge) = merge {
+                    // The channels already saw the news, only its origins change
+                    let merged = news_list.merge_origin(merge, &news.origin);
+                    println!(
+                        "merged {} into {} ({})",
+                        news.title, merged.hash, merged.origin
+                    );
+                } else {
+                    println!(
+                        "duplicate {} ({}) of {}",
+                        news.title, news.hash, duplicate.hash
+                    );
+                }
+                for channel in channels.iter().filter(|c| duplicate_channels.contains(c)) {
+                    let news = News {
+                        filtered: true,
+                        ..news.clone()
+                    };
+                    news_list.add(news, channel, gruik_config.feeds_ringsize());
+                }
+                channels.retain(|c| !duplicate_channels.contains(c));
+                if channels.is_empty() {
+                    continue;
+                }
+            }
+        }
+        i += 1;
+        if i > feed_config.maxnews {
+            println!("too many lines to post");
+            break;
+        }
+
+        for channel in channels {
+            let budget = output_queue.budget(channel);
+            let mut text = fmt_news(gruik_config, &news, Some(channel), budget);
+            match feed_config.excerpt {
+                Excerpt::Off => output_queue.privmsg(channel, &text),
+                // The excerpt gets the room left by the message
+                Excerpt::Inline => {
+                    let room = budget.saturating_sub(text.len() + 1);
+                    if let Some(excerpt) = fmt_excerpt(gruik_config, &news, feed_config, room) {
+                        text = format!("{text} {excerpt}");
+                    }
+                    output_queue.privmsg(channel, &text);
+                }
+                Excerpt::Line => {
+                    output_queue.privmsg(channel, &text);
+                    if let Some(excerpt) = fmt_excerpt(gruik_config, &news, feed_config, budget) {
+                        output_queue.privmsg(channel, &excerpt);
+                    }
+                }
+            }
+
+            // Mark item as posted
+            news_list.add(news.clone(), channel, gruik_config.feeds_ringsize());
+        }
+    }
+}
+
+/*
  * Fetch and post news from RSS feeds
+ *
+ * Every feed has its own schedule. The fetches run concurrently (up to feeds.concurrency), and
+ * their results are handled here one at a time.
  */
-fn news_fetch(gruik_config: &GruikConfig, news_list: &NewsList, irc_writer: &loirc::Writer) {
-    let feed_file = gruik_config.irc_channel() + "-feed.json";
+async fn news_fetch(
+    gruik_config: GruikConfig,
+    news_list: NewsList,
+    feeds_status: FeedsStatus,
+    output_queue: OutputQueue,
+) {
+    let http_cache_file = gruik_config.irc_channel() + "-feed-http.json";
+    let status_file = gruik_config.irc_channel() + "-feed-status.json";
 
     // load saved news
-    news_list.load_file(&feed_file);
+    news_list.load(&gruik_config);
+    let http_cache = http_fetch::HttpCache::new();
+    http_cache.load_file(&http_cache_file);
+    feeds_status.load_file(&status_file);
+
+    // When each feed has to be fetched next
+    let mut next_fetch: HashMap<String, Instant> = HashMap::new();
+    // Feeds being fetched right now
+    let mut in_flight: HashSet<String> = HashSet::new();
+    let mut fetches = JoinSet::new();
+    let mut concurrency = gruik_config.feeds_concurrency();
+    let mut semaphore = Arc::new(Semaphore::new(concurrency));
 
     loop {
-        for feed_url in gruik_config.feeds_urls() {
-            println!("Fetching {feed_url}");
-            let response = match ureq::get(feed_url.as_str()).call() {
-                Ok(r) => r,
-                Err(e) => {
-                    println!("Failed to get a response : {e:?}");
-                    continue;
-                }
-            };
+        let feeds: Vec<Feed> = gruik_config
+            .feeds()
+            .into_iter()
+            .filter(|feed| !feeds_status.is_disabled(&feed.url))
+            .collect();
+        // Feeds removed from the configuration (or disabled) are forgotten
+        next_fetch.retain(|url, _| feeds.iter().any(|feed| &feed.url == url && feed.enabled));
+        let urls: Vec<String> = gruik_config.feeds().into_iter().map(|f| f.url).collect();
+        let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+        http_cache.retain(&urls);
+        feeds_status.retain(&urls);
 
-            let mut body = response.into_body();
+        // The running fetches keep the permits of the previous semaphore
+        if gruik_config.feeds_concurrency() != concurrency {
+            concurrency = gruik_config.feeds_concurrency();
+            semaphore = Arc::new(Semaphore::new(concurrency));
+        }
 
-            let feed = match feed_rs::parser::parse(body.as_reader()) {
-                Ok(r) => r,
-                Err(e) => {
-                    println!("Failed to parse feed : {e:?}");
-                    continue;
-                }
-            };
+        for feed in feeds.iter().filter(|feed| feed.enabled) {
+            if in_flight.contains(&feed.url)
+                || next_fetch
+                    .get(&feed.url)
+                    .is_some_and(|t| *t > Instant::now())
+            {
+                continue;
+            }
+            in_flight.insert(feed.url.clone());
 
-            let mut i = 0;
-            for item in feed.entries {
-                let origin = feed
-                    .title
-                    .as_ref()
-                    .map_or_else(|| "Unknown".to_string(), |s| s.content.clone());
-                let date = item.published.map_or_else(Utc::now, |s| s);
-                let title = item.title.map_or("Unknown".to_string(), |v| v.content);
-                let mut links = vec![];
-                for link in item.links {
-                    links.push(link.href);
-                }
-                let news = News {
-                    origin,
-                    date,
-                    title,
-                    hash: mk_hash(&links),
-                    links,
-                };
-                // Check if item was already posted
-                if news_list.contains(&news) {
-                    println!("already posted {} ({})", news.title, news.hash);
+            let url = feed.url.clone();
+            let http_cache = http_cache.clone();
+            let semaphore = semaphore.clone();
+            let timeout = gruik_config.feeds_timeout();
+            fetches.spawn(async move {
+                let permit = semaphore.acquire_owned().await;
+                let result = tokio::task::spawn_blocking({
+                    let url = url.clone();
+                    move || {
+                        let _permit = permit;
+                        fetch_feed(&url, &http_cache, timeout)
+                    }
+                })
+                .await
+                .unwrap_or_else(|e| (Err(format!("Fetch task failed : {e}")), None));
+                (url, result)
+            });
+        }
+
+        // Wake up regularly anyway, to pick up the feeds added to the configuration
+        let next = next_fetch
+            .iter()
+            .filter(|(url, _)| !in_flight.contains(*url))
+            .map(|(_, t)| *t)
+            .min()
+            .unwrap_or_else(|| Instant::now() + gruik_config.feeds_frequency())
+            .min(Instant::now() + Duration::from_secs(10));
+
+        tokio::select! {
+            Some(joined) = fetches.join_next() => {
+                let Ok((url, (result, wait))) = joined else {
                     continue;
-                }
-                // don't paste news older than feeds.maxage
-                if Utc::now() - news.date > gruik_config.feeds_maxage() {
-                    println!("news too old {}", news.date);
+                };
+                in_flight.remove(&url);
+                // The feed may have been removed from the configuration in the meantime
+                let Some(feed_config) = gruik_config.feed(&url) else {
                     continue;
+                };
+                let mut frequency = feed_config.frequency;
+                match result {
+                    Ok(feed) => {
+                        feeds_status.success(&url);
+                        match feed {
+                            Some(feed) => post_news(
+                                &gruik_config,
+                                &news_list,
+                                &output_queue,
+                                &feed_config,
+                                feed,
+                            ),
+                            None => println!("{url} not modified"),
+                        }
+                    }
+                    Err(e) => {
+                        println!("{e}");
+                        let status =
+                            feeds_status.failure(&url, e, gruik_config.feeds_max_failures());
+                        // A failing feed is fetched less and less often
+                        frequency = status.backoff(frequency);
+                        if status.disabled {
+                            let text = format!(
+                                "{url} disabled after {} failures, last error : {}",
+                                status.failures,
+                                status.last_error.unwrap_or_default()
+                            );
+                            println!("{text}");
+                            notify_ops(&gruik_config, &output_queue, &text);
+                        }
+                    }
                 }
-                i += 1;
-                if i > gruik_config.feeds_maxnews() {
-                    println!("too many lines to post");
-                    break;
-                }
+                // The server may ask us to come back later than that
+                next_fetch.insert(
+                    url.clone(),
+                    Instant::now() + wait.unwrap_or_default().max(frequency),
+                );
 
-                if let Err(e) = irc_writer.raw(format!(
-                    "PRIVMSG {} {}\n",
-                    &gruik_config.irc_channel(),
-                    fmt_news(gruik_config, &news)
-                )) {
-                    println!("Failed to send an IRC message... ({e:?})");
-                }
-                thread::sleep(gruik_config.irc_delay());
+                // save news list to disk to avoid repost when restarting
+                news_list.save(&gruik_config);
+                http_cache.save_file(&http_cache_file);
+                feeds_status.save_file(&status_file);
+            }
+            () = tokio::time::sleep_until(next.into()) => {}
+        }
+    }
+}
+
+fn tls_settings(gruik_config: &GruikConfig) -> Option<irc_connection::TlsSettings> {
+    gruik_config.irc_tls().then(|| irc_connection::TlsSettings {
+        verify: gruik_config.irc_tls_verify(),
+        ca_file: gruik_config.irc_tls_ca_file(),
+        cert_file: gruik_config.irc_tls_cert(),
+        key_file: gruik_config.irc_tls_key(),
+    })
+}
+
+fn apply_config_changes(
+    gruik_config: &GruikConfig,
+    irc_writer: &irc_connection::Writer,
+    output_queue: &OutputQueue,
+    changes: &ConfigChanges,
+) {
+    println!("Configuration reloaded : {changes}");
 
-                // Mark item as posted
-                news_list.add(news, gruik_config.feeds_ringsize());
+    // Registering again takes care of the nick and the channels
+    if changes.reconnect.is_some() {
+        let tls_settings = tls_settings(gruik_config);
+        match irc_writer.set_server(
+            &gruik_config.irc_server(),
+            gruik_config.irc_port(),
+            tls_settings.as_ref(),
+        ) {
+            Ok(()) => {
+                let _ = irc_writer.raw("QUIT :Changing server\n");
+                if let Err(e) = irc_writer.disconnect() {
+                    println!("Couldn't disconnect from the IRC server : {e:?}");
+                }
             }
+            Err(e) => println!("Not changing server : {e}"),
         }
+        return;
+    }
 
-        // save news list to disk to avoid repost when restarting
-        news_list.save_file(&feed_file);
+    if let Some(nick) = &changes.nick {
+        output_queue.send_urgent(format!("NICK {nick}\n"));
+    }
+    for channel in &changes.join {
+        output_queue.send_urgent(format!("JOIN {channel}\n"));
+    }
+    for channel in &changes.part {
+        output_queue.send_urgent(format!("PART {channel}\n"));
+    }
+}
 
-        thread::sleep(gruik_config.feeds_frequency());
+// Sends a private message to every op
+fn notify_ops(gruik_config: &GruikConfig, output_queue: &OutputQueue, text: &str) {
+    // Multi-line errors are easier to read on a single line
+    let text = text.replace(['\r', '\n'], " ");
+    for op in gruik_config.ops() {
+        output_queue.privmsg(&op, &text);
     }
 }
 
-fn config_filename_notify(gruik_config: &GruikConfig) {
-    use notify::{
-        Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher, event::ModifyKind,
-    };
+fn config_filename_notify(
+    gruik_config: &GruikConfig,
+    irc_writer: &irc_connection::Writer,
+    output_queue: &OutputQueue,
+) {
+    use notify::{Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+    use std::path::Path;
+    use std::sync::mpsc::RecvTimeoutError;
+
+    // Editors usually write a file in several steps (or write a new file and rename it), we
+    // wait for things to settle before reloading
+    let debounce = std::time::Duration::from_millis(500);
 
     let (tx, rx) = std::sync::mpsc::channel();
     let mut watcher =
         RecommendedWatcher::new(tx, Config::default()).expect("Couldn't set FS event watcher");
+
+    // We watch the directory, as the file itself may be replaced
+    let config_path = Path::new(&gruik_config.filename);
+    let config_dir = match config_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
     watcher
-        .watch(
-            std::path::Path::new(&gruik_config.filename),
-            RecursiveMode::NonRecursive,
-        )
+        .watch(config_dir, RecursiveMode::NonRecursive)
         .expect("Couldn't set FS event watch on config_filename");
 
-    for res in rx {
-        match res {
-            Ok(event) => {
-                if let EventKind::Modify(ModifyKind::Data(_)) = event.kind {
-                    gruik_config.reload();
+    // When to reload, only the events of the config file itself push it back (we write other
+    // files in this directory all the time)
+    let mut reload_at: Option<Instant> = None;
+    loop {
+        let timeout = reload_at.map_or(Duration::from_secs(3600), |t| {
+            t.saturating_duration_since(Instant::now())
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_))
+                    && event
+                        .paths
+                        .iter()
+                        .any(|p| p.file_name() == config_path.file_name())
+                {
+                    reload_at = Some(Instant::now() + debounce);
                 }
             }
-            Err(error) => println!("Error: {error:?}"),
+            Ok(Err(error)) => println!("Error: {error:?}"),
+            Err(RecvTimeoutError::Timeout) => {
+                if reload_at.is_none_or(|t| t > Instant::now()) {
+                    continue;
+                }
+                reload_at = None;
+                match gruik_config.reload() {
+                    Ok(Some(changes)) => {
+                        apply_config_changes(gruik_config, irc_writer, output_queue, &changes)
+                    }
+                    // We wrote this file ourselves (or nothing changed)
+                    Ok(None) => {}
+                    Err(e) => {
+                        println!("{e}\nKeeping the previous configuration.");
+                        notify_ops(
+                            gruik_config,
+                            output_queue,
+                            &format!("Configuration reload failed, keeping the previous one : {e}"),
+                        );
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+// Returns the exit code
+fn import_opml(opml_file: &str, config_filename: String) -> i32 {
+    let gruik_config = GruikConfig::new(config_filename);
+    let feeds = match fs::read_to_string(opml_file)
+        .map_err(|e| format!("Can't read '{opml_file}' : {e}"))
+        .and_then(|xml| opml::parse(&xml))
+    {
+        Ok(r) => r,
+        Err(e) => {
+            println!("{e}");
+            return 1;
+        }
+    };
+    match gruik_config.import_feeds(feeds) {
+        Ok((_, added, skipped)) => {
+            for reason in &skipped {
+                println!("skipped {reason}");
+            }
+            println!("{added} feed(s) imported, {} skipped", skipped.len());
+            0
+        }
+        Err(e) => {
+            println!("{e}");
+            1
         }
     }
 }
@@ -519,13 +1551,35 @@ fn config_filename_notify(gruik_config: &GruikConfig) {
 async fn main() {
     let args: Vec<String> = env::args().collect();
 
+    // Subcommands : 'import-opml <file> [config]' and 'export-opml [config]'
+    match args.get(1).map(String::as_str) {
+        Some("import-opml") => {
+            let Some(opml_file) = args.get(2) else {
+                println!("usage: {} import-opml <file> [config]", args[0]);
+                std::process::exit(1);
+            };
+            let config_filename = args.get(3).map_or("config.yaml", |s| s).to_string();
+            std::process::exit(import_opml(opml_file, config_filename));
+        }
+        Some("export-opml") => {
+            let config_filename = args.get(2).map_or("config.yaml", |s| s).to_string();
+            print!("{}", opml::export(&GruikConfig::new(config_filename)));
+            std::process::exit(0);
+        }
+        _ => {}
+    }
+
     let config_filename = args.get(1).map_or("config.yaml", |s| s).to_string();
 
     // We are now creating a GruikConfig structure so that it can be shared later
     let gruik_config = GruikConfig::new(config_filename);
 
-    let (irc_writer, irc_reader) = match loirc::connect(
-        format!("{}:{}", gruik_config.irc_server(), gruik_config.irc_port()),
+    let tls_settings = tls_settings(&gruik_config);
+
+    let (irc_writer, irc_reader) = match irc_connection::connect(
+        &gruik_config.irc_server(),
+        gruik_config.irc_port(),
+        tls_settings.as_ref(),
         loirc::ReconnectionSettings::Reconnect {
             max_attempts: 10,
             delay_between_attempts: std::time::Duration::from_secs(2),
@@ -540,48 +1594,126 @@ async fn main() {
         }
     };
 
-    // register
-    let irc_nick = gruik_config.irc_nick();
-    if let Err(e) = irc_writer.raw(format!("NICK {irc_nick}\n")) {
-        println!("Can't send the 'NICK' command : {e:?}\nexiting.");
-        std::process::exit(1);
-    }
+    let irc_session = IrcSession::new();
 
-    if let Err(e) = irc_writer.raw(format!("USER {irc_nick} 0 * :{irc_nick}\n")) {
-        println!("Can't send the 'USER' command : {e:?}\nexiting.");
+    if let Err(e) = register(&gruik_config, &irc_writer, &irc_session) {
+        println!("{e}\nexiting.");
         std::process::exit(1);
     }
 
     /*
-     * From here, we are going to create 3 blocking tasks :
+     * From here, we are going to create 4 tasks :
      *
-     * #1 will run news_fetch()
-     * #2 will run config_filename_notify()
-     * #3 will run handle_irc_events()
+     * #1 will run news_fetch() (async)
+     * #2 will run config_filename_notify() (blocking)
+     * #3 will run handle_irc_events() (blocking)
+     * #4 will run irc_output::send_loop() (blocking)
+     * #5 will run http_server::run() (async), if http.bind is set
      *
-     * As soon as one of the tasks finishes, the whole program will exit!!!
+     * As soon as one of the tasks finishes, the whole program will exit!!! We also exit on
+     * SIGTERM, SIGINT and !die (see shutdown)
      */
 
     let gruik_config_clone1 = gruik_config.clone();
     let gruik_config_clone2 = gruik_config.clone();
+    let gruik_config_clone3 = gruik_config.clone();
     let news_list = NewsList::new();
     let news_list_clone1 = news_list.clone();
+    let news_list_clone2 = news_list.clone();
+    let feeds_status = FeedsStatus::new();
+    let feeds_status_clone1 = feeds_status.clone();
     let irc_writer_clone1 = irc_writer.clone();
+    let irc_writer_clone2 = irc_writer.clone();
+    let irc_writer_clone3 = irc_writer.clone();
+    let output_queue = OutputQueue::new(gruik_config.clone(), irc_session.clone());
+    let output_queue_clone1 = output_queue.clone();
+    let output_queue_clone2 = output_queue.clone();
+    let output_queue_clone3 = output_queue.clone();
+    let output_queue_clone4 = output_queue.clone();
+    let mut shutdown_requests = shutdown::requests();
 
     let mut set = JoinSet::new();
 
+    let news_fetch_task = set.spawn(news_fetch(
+        gruik_config_clone1,
+        news_list_clone1,
+        feeds_status_clone1,
+        output_queue_clone1,
+    ));
+
+    if let Some(bind) = gruik_config.http_bind() {
+        let listener = match tokio::net::TcpListener::bind(&bind).await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Can't start the HTTP server on {bind} : {e}\nexiting.");
+                std::process::exit(1);
+            }
+        };
+        set.spawn(http_server::run(
+            gruik_config.clone(),
+            news_list.clone(),
+            listener,
+        ));
+    }
+
     set.spawn_blocking(move || {
-        news_fetch(&gruik_config_clone1, &news_list_clone1, &irc_writer_clone1);
+        config_filename_notify(
+            &gruik_config_clone2,
+            &irc_writer_clone2,
+            &output_queue_clone2,
+        );
     });
 
-    set.spawn_blocking(move || config_filename_notify(&gruik_config_clone2));
+    set.spawn_blocking(move || {
+        irc_output::send_loop(&irc_writer_clone1, &output_queue_clone3);
+    });
 
     set.spawn_blocking(move || {
-        handle_irc_events(&gruik_config, &irc_writer, &irc_reader, &news_list);
+        handle_irc_events(
+            &gruik_config,
+            &irc_writer,
+            &irc_reader,
+            &irc_session,
+            &output_queue,
+            &news_list,
+            &feeds_status,
+        );
     });
 
-    // We wait for one of the blocking tasks to exit
-    set.join_next().await;
-    println!("now exiting because one the tasks finished");
-    std::process::exit(0);
+    // We wait for one of the tasks to exit, or to be asked to
+    let reason = tokio::select! {
+        reason = shutdown::signal() => reason,
+        Some(reason) = shutdown_requests.recv() => reason,
+        _ = set.join_next() => shutdown::Reason::TaskEnded,
+    };
+    println!("now exiting : {reason}");
+
+    // The news being posted are recorded before we save them
+    news_fetch_task.abort();
+    while !news_fetch_task.is_finished() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    output_queue_clone4.close();
+    if reason.requested() {
+        output_queue_clone4
+            .flush(gruik_config_clone3.irc_shutdown_timeout())
+            .await;
+    }
+    let abandoned = output_queue_clone4.clear();
+    if abandoned > 0 {
+        println!("{abandoned} line(s) not sent");
+    }
+    news_list_clone2.save(&gruik_config_clone3);
+
+    let quit_message = match &reason {
+        shutdown::Reason::Die {
+            message: Some(message),
+            ..
+        } => message.clone(),
+        _ => gruik_config_clone3.irc_quit_message(),
+    };
+    output_queue_clone4.send_urgent(format!("QUIT :{quit_message}\n"));
+    output_queue_clone4.flush(Duration::from_secs(2)).await;
+    let _ = irc_writer_clone3.disconnect();
+    std::process::exit(reason.exit_code());
 }
@@ -0,0 +1,307 @@
+/*
+ * What makes two items of a feed the same news
+ *
+ * An item is identified by its GUID (given by the feed), else by its canonical URL, else by its
+ * title. Two news of a feed are the same when they have the same GUID, or when one of them has no
+ * GUID, the same canonical URL or (if the feed asks for it with 'dedup_title') the same title.
+ * Without GUIDs and links, only the title is left : two news with the same title are the same.
+ * The same story coming from several feeds is left to 'duplicates' (see News::duplicate_of()).
+ */
+
+use feed_rs::model::Link;
+use std::collections::HashSet;
+
+use crate::News;
+
+// Query parameters that only tell where the reader comes from
+const TRACKING_PARAMS: [&str; 13] = [
+    "fbclid", "gclid", "dclid", "gclsrc", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid",
+    "mkt_tok", "_hsenc", "_hsmi", "ref_src",
+];
+
+fn is_tracking(param: &str) -> bool {
+    let param = param.to_lowercase();
+    param.starts_with("utm_") || TRACKING_PARAMS.contains(&param.as_str())
+}
+
+// The link to the news itself (not to its comments, or to an attachment)
+pub fn primary_link(links: &[Link]) -> Option<&Link> {
+    links
+        .iter()
+        .find(|link| link.rel.as_deref().is_none_or(|rel| rel == "alternate"))
+        .or_else(|| links.first())
+}
+
+/*
+ * The URL without what changes between two fetches of the same news : the scheme (http or
+ * https), "www.", the fragment, the tracking parameters, the order of the other parameters
+ * and the trailing slash
+ */
+pub fn canonical_url(link: &str) -> String {
+    let link = link.trim();
+    let mut url = match url::Url::parse(link) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        _ => return link.to_string(),
+    };
+    url.set_fragment(None);
+    let mut params: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    params.sort();
+    if params.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(params);
+    }
+
+    let host = url.host_str().unwrap_or_default();
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    let port = url
+        .port()
+        .map_or_else(String::new, |port| format!(":{port}"));
+    let path = url.path().trim_end_matches('/');
+    let query = url
+        .query()
+        .map_or_else(String::new, |query| format!("?{query}"));
+    format!("{host}{port}{path}{query}")
+}
+
+// The words of a title, whatever their case and punctuation
+pub fn title_fingerprint(title: &str) -> String {
+    title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Nearly the same titles : the same words, or at least 80% of their words in common
+pub fn similar_titles(title: &str, other: &str) -> bool {
+    let (title, other) = (title_fingerprint(title), title_fingerprint(other));
+    if title.is_empty() || other.is_empty() {
+        return false;
+    }
+    if title == other {
+        return true;
+    }
+    let words: HashSet<&str> = title.split(' ').collect();
+    let other_words: HashSet<&str> = other.split(' ').collect();
+    let all = words.union(&other_words).count();
+    // Short titles differing by one word are different news
+    all >= 5 && words.intersection(&other_words).count() * 5 >= all * 4
+}
+
+// The full identifier of a news, the hashes shown on IRC are its beginning
+pub fn news_id(news: &News) -> String {
+    use sha2::{Digest, Sha256};
+
+    let key = if !news.guid.is_empty() {
+        format!("guid\n{}\n{}", news.feed, news.guid)
+    } else if !news.url.is_empty() {
+        format!("url\n{}\n{}", news.feed, news.url)
+    } else {
+        format!("title\n{}\n{}", news.feed, news.title)
+    };
+    base16ct::lower::encode_string(&Sha256::digest(key))
+}
+
+// Length of the hashes, unless a longer one is needed to tell two news apart
+pub const HASH_LEN: usize = 8;
+
+// Two hashes are ambiguous when one is the beginning of the other
+pub fn ambiguous(hash: &str, other: &str) -> bool {
+    hash.starts_with(other) || other.starts_with(hash)
+}
+
+impl News {
+    // The hash news had before we had GUIDs and canonical URLs (empty without links)
+    pub fn legacy_hash(&self) -> String {
+        use sha2::{Digest, Sha256};
+
+        if self.links.is_empty() {
+            return String::new();
+        }
+        base16ct::lower::encode_string(&Sha256::digest(self.links.join("")))[..HASH_LEN].to_string()
+    }
+
+    // 'self' was recorded before, 'other' was just fetched
+    pub fn same_as(&self, other: &Self) -> bool {
+        // The news recorded before only have their legacy hash
+        if self.url.is_empty() && !other.links.is_empty() && self.hash == other.legacy_hash() {
+            return true;
+        }
+        if self.feed != other.feed {
+            return false;
+        }
+        if !self.guid.is_empty() && !other.guid.is_empty() {
+            return self.guid == other.guid;
+        }
+        // Without GUIDs and URLs, the title is the key (see news_id())
+        let title_only = self.guid.is_empty()
+            && self.url.is_empty()
+            && other.guid.is_empty()
+            && other.url.is_empty();
+        (title_only && self.title == other.title)
+            || (!self.url.is_empty() && self.url == other.url)
+            || (!self.fingerprint.is_empty() && self.fingerprint == other.fingerprint)
+    }
+
+    // The same story, from another feed, published within 'window'
+    pub fn duplicate_of(&self, other: &Self, window: chrono::Duration) -> bool {
+        self.feed != other.feed
+            && !self.filtered
+            && (self.date - other.date).abs() <= window
+            && ((!self.url.is_empty() && self.url == other.url)
+                || similar_titles(&self.title, &other.title))
+    }
+
+    // A merged news has the origins of all its feeds ("A, B"), whatever their case
+    pub fn has_origin(&self, origin: &str) -> bool {
+        self.origin
+            .split(", ")
+            .any(|o| o.eq_ignore_ascii_case(origin))
+    }
+}
+
+// The same test as News::same_as(), in SQL : ?1 is the GUID, ?2 the feed, ?3 the URL, ?4 the
+// fingerprint, ?5 the legacy hash and ?6 the title of the news just fetched. Every branch can
+// be served by an index
+pub const SAME_NEWS_SQL: &str = "((url = '' AND ?5 != '' AND hash = ?5)
+    OR (feed = ?2 AND ?1 != '' AND guid = ?1)
+    OR (feed = ?2 AND ?3 != '' AND url = ?3 AND (guid = '' OR ?1 = ''))
+    OR (feed = ?2 AND ?4 != '' AND fingerprint = ?4 AND (guid = '' OR ?1 = ''))
+    OR (feed = ?2 AND ?1 = '' AND ?3 = '' AND guid = '' AND url = '' AND title = ?6))";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn news(feed: &str, guid: &str, link: &str) -> News {
+        News {
+            feed: feed.to_string(),
+            channels: vec![],
+            filtered: false,
+            guid: guid.to_string(),
+            url: canonical_url(link),
+            fingerprint: String::new(),
+            origin: feed.to_string(),
+            title: "A title".to_string(),
+            authors: vec![],
+            categories: vec![],
+            summary: String::new(),
+            links: if link.is_empty() {
+                vec![]
+            } else {
+                vec![link.to_string()]
+            },
+            date: chrono::Utc::now(),
+            hash: String::new(),
+        }
+    }
+
+    #[test]
+    fn canonical_urls() {
+        assert_eq!(
+            canonical_url("https://www.example.org/post/?utm_source=x&b=2&a=1#top"),
+            canonical_url("http://example.org/post?a=1&b=2&fbclid=y")
+        );
+        // 'ref' is a real parameter on many sites
+        assert_ne!(
+            canonical_url("https://git.example.org/file?ref=main"),
+            canonical_url("https://git.example.org/file?ref=dev")
+        );
+    }
+
+    #[test]
+    fn same_news_in_a_feed() {
+        let posted = news("A", "1", "https://example.org/post?utm_medium=rss");
+        assert!(posted.same_as(&news("A", "1", "https://example.org/other")));
+        assert!(posted.same_as(&news("A", "", "https://example.org/post")));
+        // The GUID comes first
+        assert!(!posted.same_as(&news("A", "2", "https://example.org/post")));
+    }
+
+    #[test]
+    fn other_feeds_are_left_to_duplicates() {
+        let mut posted = news("A", "", "https://example.org/post");
+        assert!(!posted.same_as(&news("B", "", "https://example.org/post")));
+        let window = chrono::Duration::hours(1);
+        assert!(posted.duplicate_of(&news("B", "", "https://example.org/post"), window));
+        // An item filtered out by feed A doesn't hide the one of feed B
+        posted.filtered = true;
+        assert!(!posted.duplicate_of(&news("B", "", "https://example.org/post"), window));
+    }
+
+    #[test]
+    fn title_fingerprints() {
+        let mut posted = news("A", "", "https://example.org/post");
+        posted.fingerprint = title_fingerprint("Hello, World!");
+        let mut other = news("A", "", "https://example.org/post2");
+        other.fingerprint = title_fingerprint("hello world");
+        assert!(posted.same_as(&other));
+        assert!(!posted.same_as(&news("A", "", "https://example.org/post2")));
+    }
+
+    #[test]
+    fn same_news_in_the_store() {
+        let store = crate::news_store::NewsStore::open(":memory:").unwrap();
+        let mut posted = [
+            news("A", "1", "https://example.org/1"),
+            news("A", "", "https://example.org/2"),
+            news("B", "", "https://example.org/3"),
+            news("C", "", ""),
+        ];
+        posted[2].fingerprint = title_fingerprint("Hello, World!");
+        for (i, news) in posted.iter_mut().enumerate() {
+            news.hash = format!("{i}");
+            store.add(news, "#chan").unwrap();
+        }
+        let mut fetched = [
+            news("A", "1", "https://example.org/other"),
+            news("A", "2", "https://example.org/1"),
+            news("A", "3", "https://example.org/2"),
+            news("B", "", "https://example.org/2"),
+            news("B", "", "https://example.org/4"),
+            news("C", "", ""),
+            news("C", "", "https://example.org/5"),
+            news("C", "", ""),
+        ];
+        fetched[4].fingerprint = title_fingerprint("hello world");
+        fetched[7].title = "Another title".to_string();
+        for news in &fetched {
+            assert_eq!(
+                store.contains(news, "#chan").unwrap(),
+                posted.iter().any(|p| p.same_as(news)),
+                "{news:?}"
+            );
+        }
+    }
+
+    // An item without GUID nor link, fetched twice
+    #[test]
+    fn title_only_news() {
+        for store in [None, Some(":memory:")] {
+            let news_list = crate::NewsList::new();
+            news_list.inner.lock().unwrap().store =
+                store.map(|path| crate::news_store::NewsStore::open(path).unwrap());
+            let mut first = news("A", "", "");
+            first.hash = news_list.hash_of(&first);
+            assert_eq!(first.hash.len(), HASH_LEN);
+            news_list.add(first.clone(), "#chan", 100);
+            // Only the store remembers it
+            if store.is_some() {
+                news_list.inner.lock().unwrap().news.clear();
+            }
+
+            let again = news("A", "", "");
+            assert!(news_list.contains(&again, "#chan"));
+            assert_eq!(news_list.hash_of(&again), first.hash);
+            let mut other = news("A", "", "");
+            other.title = "Another title".to_string();
+            assert!(!news_list.contains(&other, "#chan"));
+        }
+    }
+}
@@ -0,0 +1,128 @@
+/*
+ * Include and exclude rules for the items of the feeds
+ *
+ * A rule matches an item when every field it sets matches (author and category match if any of
+ * the authors/categories does). An item is posted when it matches one of the include rules (or
+ * if there are none), and none of the exclude rules.
+ */
+
+use feed_rs::model::{Entry, Person};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::html_text::to_plain_text;
+
+// feed-rs names the authors of RSS items 'author', and puts the content of <author> (an email
+// address and/or a name) in the email field
+pub fn author_name(person: &Person) -> &str {
+    match &person.email {
+        Some(email) if person.name == "author" => email,
+        _ => &person.name,
+    }
+}
+
+// A regex, compiled when the configuration is read
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let s = String::deserialize(deserializer)?;
+        Regex::new(&s).map(Self).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilterRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<Pattern>,
+}
+
+impl FilterRule {
+    pub const fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.link.is_none()
+            && self.author.is_none()
+            && self.category.is_none()
+    }
+    // The texts are matched as they are posted (see html_text)
+    fn matches(&self, entry: &Entry) -> bool {
+        let matches_any = |pattern: &Option<Pattern>, mut values: Vec<String>| {
+            pattern.as_ref().is_none_or(|p| {
+                if values.is_empty() {
+                    values.push(String::new());
+                }
+                values.iter().any(|v| p.0.is_match(v))
+            })
+        };
+        matches_any(
+            &self.title,
+            vec![
+                entry
+                    .title
+                    .as_ref()
+                    .map_or_else(String::new, |t| to_plain_text(&t.content)),
+            ],
+        ) && matches_any(
+            &self.link,
+            entry.links.iter().map(|l| l.href.clone()).collect(),
+        ) && matches_any(
+            &self.author,
+            entry
+                .authors
+                .iter()
+                .map(|a| to_plain_text(author_name(a)))
+                .collect(),
+        ) && matches_any(
+            &self.category,
+            entry
+                .categories
+                .iter()
+                .flat_map(|c| [Some(&c.term), c.label.as_ref()])
+                .flatten()
+                .map(|c| to_plain_text(c))
+                .collect(),
+        )
+    }
+}
+
+// The rules applied to a feed (the global ones and its own ones)
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    pub include: Vec<FilterRule>,
+    pub exclude: Vec<FilterRule>,
+}
+
+impl Filters {
+    pub fn accepts(&self, entry: &Entry) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|rule| rule.matches(entry)))
+            && !self.exclude.iter().any(|rule| rule.matches(entry))
+    }
+}
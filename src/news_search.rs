@@ -0,0 +1,171 @@
+/*
+ * Search of the posted news (!search)
+ *
+ * !search <terms> [--from <origin|feed url>] [--since <duration>] [--until <duration>] [--page <n>]
+ *
+ * Every term has to be found (case insensitive) in the title, the origin or the link of a news.
+ * Terms and values can be quoted : !search "big day" --from "Tom & Co"
+ */
+
+use chrono::{DateTime, Utc};
+use duration_string::DurationString;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::News;
+
+pub const PAGE_SIZE: usize = 5;
+
+#[derive(Debug, Default)]
+pub struct Search {
+    pub terms: Vec<String>,
+    pub from: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    // Starts at 1
+    pub page: usize,
+}
+
+// Splits the arguments on spaces, except between double quotes. Empty words ("") are dropped,
+// an empty term would match every news
+fn split_args(args: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    for c in args.chars() {
+        match c {
+            '"' => {
+                if quoted && !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                quoted = !quoted;
+            }
+            ' ' if !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if quoted {
+        return Err("unclosed '\"'".to_string());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+fn ago(option: &str, value: &str) -> Result<DateTime<Utc>, String> {
+    let duration: Duration = DurationString::from_str(value)
+        .map_err(|e| format!("{option} {value} : {e}"))?
+        .into();
+    chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| Utc::now().checked_sub_signed(duration))
+        .ok_or_else(|| format!("{option} {value} : too far in the past"))
+}
+
+impl Search {
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let mut search = Self {
+            page: 1,
+            ..Self::default()
+        };
+        let mut words = split_args(args)?.into_iter();
+        while let Some(word) = words.next() {
+            let option = word.as_str();
+            if !option.starts_with("--") {
+                search.terms.push(word.to_lowercase());
+                continue;
+            }
+            let value = words
+                .next()
+                .ok_or_else(|| format!("{option} needs a value"))?;
+            match option {
+                "--from" => search.from = Some(value),
+                "--since" => search.since = Some(ago(option, &value)?),
+                "--until" => search.until = Some(ago(option, &value)?),
+                "--page" => {
+                    search.page = value
+                        .parse()
+                        .ok()
+                        .filter(|&page| page > 0)
+                        .ok_or_else(|| format!("--page {value} : not a page number"))?;
+                }
+                _ => return Err(format!("unknown option {option}")),
+            }
+        }
+        if search.terms.is_empty() {
+            return Err("nothing to search".to_string());
+        }
+        Ok(search)
+    }
+
+    // The origin given by --from can also be the URL of the feed
+    pub fn matches(&self, news: &News) -> bool {
+        let texts = [
+            news.title.to_lowercase(),
+            news.origin.to_lowercase(),
+            news.links
+                .first()
+                .map_or_else(String::new, |l| l.to_lowercase()),
+        ];
+        !news.filtered
+            && self
+                .terms
+                .iter()
+                .all(|term| texts.iter().any(|text| text.contains(term)))
+            && self
+                .from
+                .as_ref()
+                .is_none_or(|from| news.feed == *from || news.has_origin(from))
+            && self.since.is_none_or(|since| news.date >= since)
+            && self.until.is_none_or(|until| news.date <= until)
+    }
+
+    pub fn offset(&self) -> usize {
+        (self.page - 1) * PAGE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            split_args(r#"big "big day"  --from "Tom & Co""#).unwrap(),
+            vec!["big", "big day", "--from", "Tom & Co"]
+        );
+        assert_eq!(split_args(r#"a "" b"#).unwrap(), vec!["a", "b"]);
+        assert!(split_args(r#"a "b"#).is_err());
+    }
+
+    #[test]
+    fn options() {
+        let search = Search::parse(r#"Rust "Big Day" --from "Tom & Co" --page 3"#).unwrap();
+        assert_eq!(search.terms, vec!["rust", "big day"]);
+        assert_eq!(search.from.as_deref(), Some("Tom & Co"));
+        assert_eq!(search.offset(), 2 * PAGE_SIZE);
+
+        let search = Search::parse("rust --since 3d --until 1d").unwrap();
+        let (since, until) = (search.since.unwrap(), search.until.unwrap());
+        assert!(until - since > chrono::Duration::hours(47));
+    }
+
+    #[test]
+    fn limits() {
+        assert!(Search::parse("").is_err());
+        assert!(Search::parse(r#""""#).is_err());
+        assert!(Search::parse("--from Tom").is_err());
+        assert!(Search::parse("rust --from").is_err());
+        assert!(Search::parse("rust --page 0").is_err());
+        assert!(Search::parse("rust --page x").is_err());
+        assert!(Search::parse("rust --since forever").is_err());
+        assert!(Search::parse("rust --since 100000000d").is_err());
+        assert!(Search::parse("rust --sort date").is_err());
+    }
+}
@@ -0,0 +1,412 @@
+/*
+ * The SQLite store of the news (store.backend: sqlite)
+ *
+ * There is a row per news and channel it was posted to. The whole news is kept as JSON, the
+ * other columns are there to look it up.
+ */
+
+use chrono::Utc;
+use rusqlite::types::Value;
+use rusqlite::{Connection, OptionalExtension, params, params_from_iter};
+use std::time::Duration;
+
+use crate::News;
+use crate::news_dedup::{HASH_LEN, SAME_NEWS_SQL};
+use crate::news_search::{PAGE_SIZE, Search};
+
+// The schema, one step per version (PRAGMA user_version is the number of steps done)
+const MIGRATIONS: [&str; 3] = [
+    "CREATE TABLE IF NOT EXISTS news (
+        hash TEXT NOT NULL,
+        guid TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        feed TEXT NOT NULL,
+        origin TEXT NOT NULL,
+        title TEXT NOT NULL,
+        link TEXT NOT NULL,
+        -- Unix timestamps
+        date INTEGER NOT NULL,
+        posted_at INTEGER NOT NULL,
+        filtered INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (hash, channel)
+    );
+    CREATE INDEX IF NOT EXISTS news_guid ON news (guid, channel);
+    CREATE INDEX IF NOT EXISTS news_posted_at ON news (posted_at);",
+    // Canonical URL and title fingerprint, see news_dedup
+    "ALTER TABLE news ADD COLUMN url TEXT NOT NULL DEFAULT '';
+    ALTER TABLE news ADD COLUMN fingerprint TEXT NOT NULL DEFAULT '';
+    CREATE INDEX news_url ON news (url, channel);",
+    // The lookups of news_dedup::SAME_NEWS_SQL are always within a feed
+    "DROP INDEX news_guid;
+    DROP INDEX news_url;
+    CREATE INDEX news_feed_guid ON news (feed, guid);
+    CREATE INDEX news_feed_url ON news (feed, url);
+    CREATE INDEX news_feed_fingerprint ON news (feed, fingerprint);
+    CREATE INDEX news_feed_title ON news (feed, title);",
+];
+
+// The text is taken literally by LIKE ... ESCAPE '\'
+fn like_literal(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+pub struct NewsStore {
+    conn: Connection,
+}
+
+impl NewsStore {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| format!("Can't open {path} : {e}"))?;
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(|e| format!("Can't read the version of {path} : {e}"))?;
+        for (i, migration) in MIGRATIONS
+            .iter()
+            .enumerate()
+            .skip(usize::try_from(version).unwrap_or_default())
+        {
+            conn.execute_batch(&format!(
+                "BEGIN; {migration} PRAGMA user_version = {}; COMMIT;",
+                i + 1
+            ))
+            .map_err(|e| format!("Can't update the tables of {path} : {e}"))?;
+        }
+        Ok(Self { conn })
+    }
+
+    pub fn is_empty(&self) -> Result<bool, String> {
+        self.conn
+            .query_row("SELECT NOT EXISTS (SELECT 1 FROM news)", [], |row| {
+                row.get(0)
+            })
+            .map_err(|e| e.to_string())
+    }
+
+    fn insert(conn: &Connection, news: &News, channel: &str, posted_at: i64) -> Result<(), String> {
+        let data = serde_json::to_string(news).map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT OR IGNORE INTO news
+                (hash, guid, url, fingerprint, channel, feed, origin, title, link, date,
+                 posted_at, filtered, data)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                news.hash,
+                news.guid,
+                news.url,
+                news.fingerprint,
+                channel,
+                news.feed,
+                news.origin,
+                news.title,
+                news.links.first().map_or("", String::as_str),
+                news.date.timestamp(),
+                posted_at,
+                news.filtered,
+                data,
+            ],
+        )
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+    }
+
+    // Records the news as posted to 'channel' now
+    pub fn add(&self, news: &News, channel: &str) -> Result<(), String> {
+        Self::insert(&self.conn, news, channel, Utc::now().timestamp())
+    }
+
+    // Records news posted before we had a store (we only know their date)
+    pub fn import(&mut self, news_list: &[News]) -> Result<usize, String> {
+        let transaction = self.conn.transaction().map_err(|e| e.to_string())?;
+        for news in news_list {
+            for channel in &news.channels {
+                Self::insert(&transaction, news, channel, news.date.timestamp())?;
+            }
+        }
+        transaction.commit().map_err(|e| e.to_string())?;
+        Ok(news_list.len())
+    }
+
+    // Returns true if the same news was posted to 'channel' (see News::same_as())
+    pub fn contains(&self, news: &News, channel: &str) -> Result<bool, String> {
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT EXISTS (SELECT 1 FROM news WHERE {SAME_NEWS_SQL} AND channel = ?7)"
+                ),
+                params![
+                    news.guid,
+                    news.feed,
+                    news.url,
+                    news.fingerprint,
+                    news.legacy_hash(),
+                    news.title,
+                    channel
+                ],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())
+    }
+
+    // Returns the hash of the same news, if it was posted to any channel
+    pub fn find_hash(&self, news: &News) -> Result<Option<String>, String> {
+        self.conn
+            .query_row(
+                &format!("SELECT hash FROM news WHERE {SAME_NEWS_SQL} LIMIT 1"),
+                params![
+                    news.guid,
+                    news.feed,
+                    news.url,
+                    news.fingerprint,
+                    news.legacy_hash(),
+                    news.title
+                ],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    /*
+     * Returns true if 'hash' could be mistaken for the hash of a recorded news : a recorded hash
+     * starts with it (hashes are lowercase hex, 'g' comes after all of them), or it starts with a
+     * recorded hash (which is at least HASH_LEN long). Both use the primary key
+     */
+    pub fn is_ambiguous(&self, hash: &str) -> Result<bool, String> {
+        let prefixes: Vec<&str> = (HASH_LEN.min(hash.len())..=hash.len())
+            .filter_map(|len| hash.get(..len))
+            .collect();
+        let placeholders = (2..prefixes.len() + 2)
+            .map(|n| format!("?{n}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT EXISTS (SELECT 1 FROM news WHERE hash >= ?1 AND hash < ?1 || 'g')
+                        OR EXISTS (SELECT 1 FROM news WHERE hash IN ({placeholders}))"
+                ),
+                params_from_iter(std::iter::once(hash).chain(prefixes)),
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())
+    }
+
+    // Gives a new origin to a news (in every channel)
+    pub fn set_origin(&self, hash: &str, origin: &str) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE news SET origin = ?2, data = json_set(data, '$.origin', ?2) WHERE hash = ?1",
+                params![hash, origin],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    // Runs a query returning the data and the channels of news
+    fn query_news(&self, query: &str, params: &[Value]) -> Result<Vec<News>, String> {
+        let mut statement = self.conn.prepare(query).map_err(|e| e.to_string())?;
+        let rows = statement
+            .query_map(params_from_iter(params), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(|e| e.to_string())?;
+        let mut news_list = Vec::new();
+        for row in rows {
+            let (data, channels) = row.map_err(|e| e.to_string())?;
+            let mut news: News = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+            news.channels = channels.split(',').map(ToString::to_string).collect();
+            news_list.push(news);
+        }
+        Ok(news_list)
+    }
+
+    // Returns the n latest news, oldest first
+    pub fn latest(&self, n: usize) -> Result<Vec<News>, String> {
+        let mut news_list = self.query_news(
+            "SELECT data, group_concat(channel) FROM news GROUP BY hash
+             ORDER BY max(posted_at) DESC, max(rowid) DESC LIMIT ?1",
+            &[Value::Integer(i64::try_from(n).unwrap_or(i64::MAX))],
+        )?;
+        news_list.reverse();
+        Ok(news_list)
+    }
+
+    // Returns a page of the news found (newest first), and how many were found
+    pub fn search(&self, search: &Search) -> Result<(Vec<News>, usize), String> {
+        let mut conditions = vec!["filtered = 0".to_string()];
+        let mut params = Vec::new();
+        for term in &search.terms {
+            params.push(Value::Text(format!("%{}%", like_literal(term))));
+            let n = params.len();
+            conditions.push(format!(
+                "(title LIKE ?{n} ESCAPE '\\' OR origin LIKE ?{n} ESCAPE '\\' \
+                 OR link LIKE ?{n} ESCAPE '\\')"
+            ));
+        }
+        if let Some(from) = &search.from {
+            params.push(Value::Text(from.clone()));
+            params.push(Value::Text(format!("%,{},%", like_literal(from))));
+            let n = params.len();
+            // A merged news has several origins ("A, B", see News::has_origin())
+            conditions.push(format!(
+                "(feed = ?{} OR ',' || replace(origin, ', ', ',') || ',' LIKE ?{n} ESCAPE '\\')",
+                n - 1
+            ));
+        }
+        if let Some(since) = search.since {
+            params.push(Value::Integer(since.timestamp()));
+            conditions.push(format!("date >= ?{}", params.len()));
+        }
+        if let Some(until) = search.until {
+            params.push(Value::Integer(until.timestamp()));
+            conditions.push(format!("date <= ?{}", params.len()));
+        }
+        let conditions = conditions.join(" AND ");
+
+        let found: i64 = self
+            .conn
+            .query_row(
+                &format!("SELECT count(DISTINCT hash) FROM news WHERE {conditions}"),
+                params_from_iter(&params),
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        let n = params.len();
+        params.push(Value::Integer(i64::try_from(PAGE_SIZE).unwrap_or(i64::MAX)));
+        params.push(Value::Integer(
+            i64::try_from(search.offset()).unwrap_or(i64::MAX),
+        ));
+        let news_list = self.query_news(
+            &format!(
+                "SELECT data, group_concat(channel) FROM news WHERE {conditions} GROUP BY hash
+                 ORDER BY max(date) DESC, max(rowid) DESC LIMIT ?{} OFFSET ?{}",
+                n + 1,
+                n + 2
+            ),
+            &params,
+        )?;
+        Ok((news_list, usize::try_from(found).unwrap_or_default()))
+    }
+
+    // Forgets the news posted more than 'max_age' ago, and the oldest ones beyond 'max_news'
+    pub fn prune(
+        &self,
+        max_age: Option<Duration>,
+        max_news: Option<usize>,
+    ) -> Result<usize, String> {
+        let mut deleted = 0;
+        if let Some(max_age) = max_age {
+            let max_age = i64::try_from(max_age.as_secs()).unwrap_or(i64::MAX);
+            deleted += self
+                .conn
+                .execute(
+                    "DELETE FROM news WHERE posted_at < ?1",
+                    [Utc::now().timestamp().saturating_sub(max_age)],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        if let Some(max_news) = max_news {
+            deleted += self
+                .conn
+                .execute(
+                    "DELETE FROM news WHERE hash NOT IN (SELECT hash FROM news GROUP BY hash
+                        ORDER BY max(posted_at) DESC, max(rowid) DESC LIMIT ?1)",
+                    [i64::try_from(max_news).unwrap_or(i64::MAX)],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn news(hash: &str) -> News {
+        serde_json::from_value(serde_json::json!({
+            "feed": "https://example.org/feed",
+            "url": format!("example.org/{hash}"),
+            "origin": "Example",
+            "title": "A title",
+            "links": [format!("https://example.org/{hash}")],
+            "date": "2026-10-16T00:00:00Z",
+            "hash": hash,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn ambiguous_hashes() {
+        let store = NewsStore::open(":memory:").unwrap();
+        store.add(&news("0123abcd"), "#chan").unwrap();
+        store.add(&news("fedcba9876"), "#chan").unwrap();
+        assert!(store.is_ambiguous("0123abcd").unwrap());
+        assert!(store.is_ambiguous("0123abcdef").unwrap());
+        assert!(store.is_ambiguous("fedcba98").unwrap());
+        assert!(!store.is_ambiguous("0123abce").unwrap());
+        assert!(!store.is_ambiguous("fedcba99").unwrap());
+    }
+
+    // The lookups done for every item of every fetch don't read the whole table
+    #[test]
+    fn lookups_use_indexes() {
+        let store = NewsStore::open(":memory:").unwrap();
+        let news = news("0123abcd");
+        let plan = |query: &str, params: &[&dyn rusqlite::ToSql]| -> String {
+            let mut statement = store
+                .conn
+                .prepare(&format!("EXPLAIN QUERY PLAN {query}"))
+                .unwrap();
+            let rows = statement
+                .query_map(params, |row| row.get::<_, String>(3))
+                .unwrap();
+            rows.map(Result::unwrap).collect::<Vec<_>>().join("\n")
+        };
+        let same_news = plan(
+            &format!("SELECT hash FROM news WHERE {SAME_NEWS_SQL} AND channel = ?7"),
+            params![
+                news.guid,
+                news.feed,
+                news.url,
+                news.fingerprint,
+                news.legacy_hash(),
+                news.title,
+                "#chan"
+            ],
+        );
+        assert!(!same_news.contains("SCAN news"), "{same_news}");
+        let ambiguous = plan(
+            "SELECT EXISTS (SELECT 1 FROM news WHERE hash >= ?1 AND hash < ?1 || 'g')
+                OR EXISTS (SELECT 1 FROM news WHERE hash IN (?2))",
+            params!["0123abcd", "0123abcd"],
+        );
+        assert!(!ambiguous.contains("SCAN news"), "{ambiguous}");
+    }
+
+    // A merged news is found from any of its origins
+    #[test]
+    fn search_merged_origins() {
+        let store = NewsStore::open(":memory:").unwrap();
+        let mut merged = news("0123abcd");
+        merged.origin = "Example, Other_Blog".to_string();
+        store.add(&merged, "#chan").unwrap();
+        store.add(&news("fedcba98"), "#chan").unwrap();
+
+        let found = |args: &str| {
+            let search = Search::parse(args).unwrap();
+            let (news_list, n) = store.search(&search).unwrap();
+            assert_eq!(n, news_list.len());
+            assert!(news_list.iter().all(|news| search.matches(news)));
+            n
+        };
+        assert_eq!(found("title --from other_blog"), 1);
+        assert_eq!(found("title --from example"), 2);
+        assert_eq!(found("title --from https://example.org/feed"), 2);
+        assert_eq!(found("title --from Other"), 0);
+        assert_eq!(found("title --from Other%"), 0);
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * OPML import and export of the feeds list (http://opml.org/spec2.opml)
+ *
+ * The title of an outline becomes the name of the feed. Categories (the 'category' attribute, or
+ * the outlines the feed is nested in) that are channel names become the channels of the feed,
+ * the other ones are dropped since the configuration has no place for them. OPML has no word
+ * for a disabled feed, we use a 'disabled="true"' attribute (the other tools ignore it).
+ */
+
+use quick_xml::encoding::Decoder;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+
+use crate::gruik_config::{GruikConfig, NewFeed};
+
+fn is_channel(name: &str) -> bool {
+    name.starts_with(['#', '&']) && name.len() > 1
+}
+
+fn attribute(element: &BytesStart, name: &str, decoder: Decoder) -> Result<Option<String>, String> {
+    for attr in element.attributes() {
+        let attr = attr.map_err(|e| e.to_string())?;
+        if attr.key.local_name().as_ref() == name.as_bytes() {
+            return attr
+                .decode_and_unescape_value(decoder)
+                .map(|v| Some(v.trim().to_string()))
+                .map_err(|e| e.to_string());
+        }
+    }
+    Ok(None)
+}
+
+pub fn parse(xml: &str) -> Result<Vec<NewFeed>, String> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut feeds = Vec::new();
+    // The text of the outlines we are in
+    let mut folders: Vec<String> = Vec::new();
+
+    loop {
+        let (element, nested) = match reader.read_event() {
+            Ok(Event::Start(e)) => (e, true),
+            Ok(Event::Empty(e)) => (e, false),
+            Ok(Event::End(e)) => {
+                if e.local_name().as_ref() == b"outline" {
+                    folders.pop();
+                }
+                continue;
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => continue,
+            Err(e) => {
+                return Err(format!(
+                    "Can't parse the OPML file at position {} : {e}",
+                    reader.error_position()
+                ));
+            }
+        };
+        if element.local_name().as_ref() != b"outline" {
+            continue;
+        }
+        let text = attribute(&element, "title", reader.decoder())?
+            .or(attribute(&element, "text", reader.decoder())?)
+            .filter(|t| !t.is_empty());
+
+        if let Some(url) = attribute(&element, "xmlUrl", reader.decoder())? {
+            let mut channels: Vec<String> = Vec::new();
+            let categories = attribute(&element, "category", reader.decoder())?.unwrap_or_default();
+            for category in folders
+                .iter()
+                .map(String::as_str)
+                .chain(categories.split(',').map(|c| c.trim().trim_matches('/')))
+            {
+                if is_channel(category) && !channels.iter().any(|c| c == category) {
+                    channels.push(category.to_string());
+                }
+            }
+            feeds.push(NewFeed {
+                // A title that is just the URL is not worth keeping
+                name: text.clone().filter(|t| *t != url),
+                url,
+                channels,
+                enabled: attribute(&element, "disabled", reader.decoder())?
+                    .is_none_or(|disabled| disabled != "true"),
+            });
+        }
+        if nested {
+            folders.push(text.unwrap_or_default());
+        }
+    }
+    Ok(feeds)
+}
+
+pub fn export(gruik_config: &GruikConfig) -> String {
+    let default_channels = vec![gruik_config.irc_channel()];
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <opml version=\"2.0\">\n\
+         \x20 <head>\n\
+         \x20   <title>{}</title>\n\
+         \x20 </head>\n\
+         \x20 <body>\n",
+        escape(gruik_config.irc_nick())
+    );
+    for feed in gruik_config.feeds() {
+        let text = feed.name.as_ref().unwrap_or(&feed.url);
+        xml += &format!(
+            "    <outline type=\"rss\" text=\"{}\" title=\"{}\" xmlUrl=\"{}\"",
+            escape(text),
+            escape(text),
+            escape(&feed.url)
+        );
+        // The feeds posted to irc.channel don't need a category
+        if feed.channels != default_channels {
+            xml += &format!(" category=\"{}\"", escape(feed.channels.join(",")));
+        }
+        if !feed.enabled {
+            xml += " disabled=\"true\"";
+        }
+        xml += "/>\n";
+    }
+    xml + "  </body>\n</opml>\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("gruik-test-{}-opml.yml", std::process::id()));
+        std::fs::write(
+            &path,
+            "irc:\n  server: irc.example.org\n  nick: gruik\n  channel: '#news'\n\
+             feeds:\n  urls:\n\
+             \x20   - https://a.example/feed\n\
+             \x20   - url: https://b.example/feed?a=1&b=2\n\
+             \x20     name: B & \"co\"\n\
+             \x20     channels: ['#b', '#news']\n\
+             \x20     enabled: false\n",
+        )
+        .unwrap();
+        let gruik_config = GruikConfig::new(path.to_string_lossy().to_string());
+        let _ = std::fs::remove_file(&path);
+
+        let feeds = parse(&export(&gruik_config)).unwrap();
+        assert_eq!(feeds.len(), 2);
+        assert_eq!(feeds[0].url, "https://a.example/feed");
+        assert_eq!(feeds[0].name, None);
+        assert!(feeds[0].channels.is_empty());
+        assert!(feeds[0].enabled);
+        assert_eq!(feeds[1].url, "https://b.example/feed?a=1&b=2");
+        assert_eq!(feeds[1].name.as_deref(), Some("B & \"co\""));
+        assert_eq!(feeds[1].channels, vec!["#b", "#news"]);
+        assert!(!feeds[1].enabled);
+    }
+
+    #[test]
+    fn nested_outlines() {
+        let xml = r##"<?xml version="1.0"?>
+            <opml version="2.0"><body>
+              <outline text="#tech">
+                <outline text="A" xmlUrl="https://a.example/feed" category="/#a,/Misc"/>
+                <outline text="https://b.example/feed" xmlUrl="https://b.example/feed"/>
+              </outline>
+              <outline text="C" xmlUrl="https://c.example/feed" disabled="false"/>
+            </body></opml>"##;
+        let feeds = parse(xml).unwrap();
+        assert_eq!(feeds.len(), 3);
+        assert_eq!(feeds[0].name.as_deref(), Some("A"));
+        assert_eq!(feeds[0].channels, vec!["#tech", "#a"]);
+        assert_eq!(feeds[1].name, None);
+        assert_eq!(feeds[1].channels, vec!["#tech"]);
+        assert!(feeds[2].channels.is_empty());
+        assert!(feeds[2].enabled);
+        assert!(parse("<opml><body><outline text=\"a\"></body>").is_err());
+    }
+}
@@ -0,0 +1,86 @@
+/*
+ * Why and when we stop
+ *
+ * SIGTERM, SIGINT and !die ask for a graceful shutdown : we stop fetching, give the waiting
+ * lines irc.shutdown_timeout to be sent, save the news and leave with 'QUIT :irc.quit_message'.
+ * When one of the tasks stops on its own, the waiting lines are abandoned.
+ */
+
+use std::fmt;
+use std::sync::OnceLock;
+use tokio::sync::mpsc;
+
+pub enum Reason {
+    Signal(&'static str),
+    // !die, with an optional quit message
+    Die { by: String, message: Option<String> },
+    // One of the tasks finished (we lost the IRC connection for good for example)
+    TaskEnded,
+}
+
+impl Reason {
+    // Whether the shutdown was asked for (and the waiting lines deserve to be sent)
+    pub const fn requested(&self) -> bool {
+        !matches!(self, Self::TaskEnded)
+    }
+    pub const fn exit_code(&self) -> i32 {
+        if self.requested() { 0 } else { 1 }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Signal(signal) => write!(f, "received {signal}"),
+            Self::Die { by, .. } => write!(f, "!die from {by}"),
+            Self::TaskEnded => write!(f, "one of the tasks finished"),
+        }
+    }
+}
+
+// Where the shutdown requests (!die) go, set by requests()
+static REQUESTS: OnceLock<mpsc::UnboundedSender<Reason>> = OnceLock::new();
+
+// Returns the receiver of the shutdown requests (once)
+pub fn requests() -> mpsc::UnboundedReceiver<Reason> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    REQUESTS
+        .set(sender)
+        .expect("Shutdown requests already taken!");
+    receiver
+}
+
+// Asks for a shutdown, like a signal would (from any thread)
+pub fn die(by: &str, message: Option<String>) {
+    let reason = Reason::Die {
+        by: by.to_string(),
+        message,
+    };
+    // Nobody listens anymore when we are already shutting down
+    if let Some(sender) = REQUESTS.get() {
+        let _ = sender.send(reason);
+    }
+}
+
+// Waits for SIGTERM or SIGINT (only SIGINT, as Ctrl-C, outside of Unix)
+pub async fn signal() -> Reason {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => tokio::select! {
+                _ = sigterm.recv() => return Reason::Signal("SIGTERM"),
+                _ = tokio::signal::ctrl_c() => return Reason::Signal("SIGINT"),
+            },
+            Err(e) => println!("Can't handle SIGTERM : {e}"),
+        }
+    }
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => Reason::Signal("SIGINT"),
+        Err(e) => {
+            println!("Can't handle SIGINT : {e}");
+            std::future::pending().await
+        }
+    }
+}
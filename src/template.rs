@@ -0,0 +1,309 @@
+/*
+ * Templates of the news messages
+ *
+ * Placeholders : {origin} {title} {link} {hash} {date} {author} {category} {summary}
+ * Colors and styles : {red}, {bold}, ... (any color of irc.colors values), {reset}
+ * Colors of irc.colors : {origin_color} {title_color} {link_color} {hash_color} {excerpt_color}
+ * '{{' and '}}' are literal braces.
+ *
+ * Templates are parsed when the configuration is read, so that a broken one is caught early.
+ */
+
+use crate::News;
+use crate::gruik_config::IrcColor;
+use crate::irc_output;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub const DEFAULT_TEMPLATE: &str = "[{origin_color}{origin}{reset}] {title_color}{title}{reset} \
+                                    {link_color}{link}{reset} {hash_color}#{hash}{reset}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Origin,
+    Title,
+    Link,
+    Hash,
+    Date,
+    Author,
+    Category,
+    Summary,
+}
+
+// The colors of irc.colors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorKey {
+    Origin,
+    Title,
+    Link,
+    Hash,
+    Excerpt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+    Style(IrcColor),
+    Color(ColorKey),
+}
+
+impl Part {
+    fn from_name(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "origin" => Self::Field(Field::Origin),
+            "title" => Self::Field(Field::Title),
+            "link" => Self::Field(Field::Link),
+            "hash" => Self::Field(Field::Hash),
+            "date" => Self::Field(Field::Date),
+            "author" => Self::Field(Field::Author),
+            "category" => Self::Field(Field::Category),
+            "summary" => Self::Field(Field::Summary),
+            "origin_color" => Self::Color(ColorKey::Origin),
+            "title_color" => Self::Color(ColorKey::Title),
+            "link_color" => Self::Color(ColorKey::Link),
+            "hash_color" => Self::Color(ColorKey::Hash),
+            "excerpt_color" => Self::Color(ColorKey::Excerpt),
+            "reset" => Self::Style(IrcColor::Reset),
+            other => Self::Style(
+                other
+                    .parse()
+                    .map_err(|_| format!("unknown placeholder '{{{other}}}'"))?,
+            ),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        if source.contains(|c: char| c.is_control()) {
+            return Err("templates can't contain control characters".to_string());
+        }
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' | '}' if chars.peek() == Some(&c) => {
+                    chars.next();
+                    text.push(c);
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed '{{{name}'")),
+                        }
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::from_name(name.trim())?);
+                }
+                '}' => return Err("unmatched '}' (write '}}')".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    // Renders the template, shortening the fields (never the link, nor the hash) until the
+    // message is at most 'max_len' bytes long
+    pub fn render(
+        &self,
+        news: &News,
+        colors: impl Fn(ColorKey) -> IrcColor,
+        max_len: usize,
+    ) -> String {
+        let mut values: Vec<(Field, String)> = [
+            Field::Origin,
+            Field::Title,
+            Field::Link,
+            Field::Hash,
+            Field::Date,
+            Field::Author,
+            Field::Category,
+            Field::Summary,
+        ]
+        .into_iter()
+        .map(|field| (field, field_value(news, field)))
+        .collect();
+        let mut res = self.assemble(&values, &colors);
+
+        for field in SHORTENED_FIELDS {
+            if res.len() <= max_len {
+                break;
+            }
+            let count = self
+                .parts
+                .iter()
+                .filter(|part| **part == Part::Field(field))
+                .count();
+            if count == 0 {
+                continue;
+            }
+            let excess = (res.len() - max_len).div_ceil(count);
+            for (f, value) in &mut values {
+                if *f == field {
+                    *value = irc_output::ellipsize(value, value.len().saturating_sub(excess));
+                }
+            }
+            res = self.assemble(&values, &colors);
+        }
+        res
+    }
+
+    fn assemble(
+        &self,
+        values: &[(Field, String)],
+        colors: impl Fn(ColorKey) -> IrcColor,
+    ) -> String {
+        let mut res = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => res += text,
+                Part::Style(style) => res += &style.to_string(),
+                Part::Color(key) => res += &colors(*key).to_string(),
+                Part::Field(field) => {
+                    if let Some((_, value)) = values.iter().find(|(f, _)| f == field) {
+                        res += value;
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+// The fields we shorten when a message is too long, in that order
+const SHORTENED_FIELDS: [Field; 5] = [
+    Field::Summary,
+    Field::Title,
+    Field::Category,
+    Field::Author,
+    Field::Origin,
+];
+
+fn field_value(news: &News, field: Field) -> String {
+    match field {
+        Field::Origin => news.origin.clone(),
+        Field::Title => news.title.clone(),
+        Field::Link => news.links.first().cloned().unwrap_or_default(),
+        Field::Hash => news.hash.clone(),
+        Field::Date => news.date.format("%F %R").to_string(),
+        Field::Author => news.authors.join(", "),
+        Field::Category => news.categories.join(", "),
+        Field::Summary => news.summary.clone(),
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Self::parse(DEFAULT_TEMPLATE).expect("Wrong default!")
+    }
+}
+
+impl PartialEq for Template {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let source = String::deserialize(deserializer)?;
+        Self::parse(&source).map_err(|e| D::Error::custom(format!("template '{source}' : {e}")))
+    }
+}
+
+impl Serialize for Template {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn news() -> News {
+        serde_json::from_value(serde_json::json!({
+            "origin": "Example",
+            "title": "A rather long title about nothing",
+            "summary": "What the news is about, in a few words",
+            "links": ["https://example.org/post"],
+            "date": "2026-10-16T12:34:00Z",
+            "hash": "0123abcd",
+        }))
+        .unwrap()
+    }
+
+    fn render(source: &str, max_len: usize) -> String {
+        Template::parse(source)
+            .unwrap()
+            .render(&news(), |_| IrcColor::Bold, max_len)
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            render("[{origin}] {title} {link} #{hash} {date}", 512),
+            "[Example] A rather long title about nothing https://example.org/post #0123abcd \
+             2026-10-16 12:34"
+        );
+        assert_eq!(
+            render("{red}{title_color}x{ reset }", 512),
+            "\x0304\x02x\x0f"
+        );
+        assert_eq!(
+            Template::parse("{nope}").unwrap_err(),
+            "unknown placeholder '{nope}'"
+        );
+        assert!(Template::parse("a\x02b").is_err());
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(render("{{{origin}}} }}{{", 512), "{Example} }{");
+        assert_eq!(Template::parse("{title").unwrap_err(), "unclosed '{title'");
+        assert!(Template::parse("title}").is_err());
+    }
+
+    #[test]
+    fn shortening() {
+        let full = render("{title} {summary} {link}", 512);
+        let shortened = render("{title} {summary} {link}", full.len() - 10);
+        assert_eq!(shortened.len(), full.len() - 10);
+        // The summary goes first
+        assert!(shortened.starts_with("A rather long title about nothing What"));
+        assert!(shortened.ends_with("… https://example.org/post"));
+
+        // Then the title, never the link nor the hash
+        let shortened = render("{title} {summary} {link} {hash}", 40);
+        assert!(shortened.ends_with(" https://example.org/post 0123abcd"));
+        assert!(shortened.len() <= 40);
+        let too_short = render("{title} {link} {hash}", 10);
+        assert!(too_short.ends_with("https://example.org/post 0123abcd"));
+    }
+}
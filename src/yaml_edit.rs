@@ -0,0 +1,325 @@
+/*
+ * In-place edition of a block sequence in a YAML document
+ *
+ * serde_yaml drops comments and the layout of the file when serializing, so instead of writing
+ * the whole configuration again, only the lines of the edited sequence are touched. Anything
+ * we don't understand (flow mappings, multi-line flow sequences, ...) is an error, and the
+ * caller can fall back to a full serialization.
+ */
+
+pub enum SeqEdit {
+    // Items are YAML documents, as produced by serde_yaml::to_string()
+    Append(Vec<String>),
+    Remove(usize),
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+// Blank lines and comments don't end a block
+fn is_content(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#')
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '#') if previous.is_whitespace() => return &line[..i],
+            _ => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+// Returns the value following 'key:' on this line
+fn key_value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let rest = strip_comment(line)
+        .trim()
+        .strip_prefix(key)?
+        .strip_prefix(':')?;
+    (rest.is_empty() || rest.starts_with(' ')).then(|| rest.trim())
+}
+
+// "- item" lines, with the item's own lines indented below the dash
+fn render_item(item: &str, indent: usize) -> Vec<String> {
+    let pad = " ".repeat(indent);
+    item.trim_end_matches('\n')
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            if i == 0 {
+                format!("{pad}- {line}")
+            } else {
+                format!("{pad}  {line}")
+            }
+        })
+        .collect()
+}
+
+// A bare 'key:' is null, not an empty sequence
+fn empty_or_block(key: &str, indent: usize, empty: bool) -> String {
+    let pad = " ".repeat(indent);
+    if empty {
+        format!("{pad}{key}: []")
+    } else {
+        format!("{pad}{key}:")
+    }
+}
+
+// Applies the edit on the items and renders them as a block sequence
+fn render_sequence(
+    mut items: Vec<String>,
+    edit: SeqEdit,
+    indent: usize,
+) -> Result<Vec<String>, String> {
+    match edit {
+        SeqEdit::Append(new_items) => items.extend(new_items),
+        SeqEdit::Remove(index) if index < items.len() => {
+            items.remove(index);
+        }
+        SeqEdit::Remove(_) => return Err("bad index number".to_string()),
+    }
+    Ok(items
+        .iter()
+        .flat_map(|item| render_item(item, indent))
+        .collect())
+}
+
+pub fn edit_sequence(
+    yaml: &str,
+    section: &str,
+    key: &str,
+    edit: SeqEdit,
+) -> Result<String, String> {
+    let mut lines: Vec<String> = yaml.lines().map(ToString::to_string).collect();
+
+    // The top-level block
+    let start = lines
+        .iter()
+        .position(|l| indent(l) == 0 && key_value(l, section) == Some(""))
+        .ok_or_else(|| format!("no '{section}:' block"))?;
+    let end = (start + 1..lines.len())
+        .find(|i| is_content(&lines[*i]) && indent(&lines[*i]) == 0)
+        .unwrap_or(lines.len());
+    let child_indent = (start + 1..end)
+        .find(|i| is_content(&lines[*i]))
+        .map_or(2, |i| indent(&lines[i]));
+
+    // The key in this block
+    let Some(key_line) = (start + 1..end)
+        .find(|i| indent(&lines[*i]) == child_indent && key_value(&lines[*i], key).is_some())
+    else {
+        let new_lines = render_sequence(vec![], edit, child_indent + 2)?;
+        lines.splice(
+            start + 1..start + 1,
+            std::iter::once(format!("{}{key}:", " ".repeat(child_indent))).chain(new_lines),
+        );
+        return Ok(lines.join("\n") + "\n");
+    };
+    let value = key_value(&lines[key_line], key).unwrap_or_default();
+
+    // A flow sequence ('urls: [a, b]') is turned into a block sequence
+    if !value.is_empty() {
+        let items: Vec<serde_yaml::Value> = serde_yaml::from_str(value)
+            .map_err(|e| format!("can't parse the value of '{key}' : {e}"))?;
+        let items = items
+            .iter()
+            .map(serde_yaml::to_string)
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| e.to_string())?;
+        let new_lines = render_sequence(items, edit, child_indent + 2)?;
+        lines[key_line] = empty_or_block(key, child_indent, new_lines.is_empty());
+        lines.splice(key_line + 1..key_line + 1, new_lines);
+        return Ok(lines.join("\n") + "\n");
+    }
+
+    // A block sequence may be indented like its key
+    let seq_indent = (key_line + 1..end)
+        .find(|i| is_content(&lines[*i]))
+        .map(|i| &lines[i])
+        .filter(|l| indent(l) >= child_indent && l.trim_start().starts_with('-'))
+        .map_or(child_indent + 2, |l| indent(l));
+
+    // (first line, last line) of every item
+    let mut items: Vec<(usize, usize)> = Vec::new();
+    for (i, line) in lines.iter().enumerate().take(end).skip(key_line + 1) {
+        if !is_content(line) {
+            continue;
+        }
+        if indent(line) == seq_indent && line.trim_start().starts_with('-') {
+            items.push((i, i));
+        } else if indent(line) > seq_indent
+            && let Some(item) = items.last_mut()
+        {
+            item.1 = i;
+        } else {
+            break;
+        }
+    }
+
+    match edit {
+        SeqEdit::Append(new_items) => {
+            let at = items.last().map_or(key_line + 1, |item| item.1 + 1);
+            let new_lines: Vec<String> = new_items
+                .iter()
+                .flat_map(|item| render_item(item, seq_indent))
+                .collect();
+            lines.splice(at..at, new_lines);
+        }
+        SeqEdit::Remove(index) => {
+            let (first, last) = items
+                .get(index)
+                .ok_or_else(|| "bad index number".to_string())?;
+            let (first, last) = (*first, *last);
+            lines.drain(first..=last);
+            if items.len() == 1 {
+                lines[key_line] = empty_or_block(key, child_indent, true);
+            }
+        }
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(yaml: &str) -> serde_yaml::Value {
+        let doc: serde_yaml::Value = serde_yaml::from_str(yaml).expect("invalid YAML");
+        doc["feeds"]["urls"].clone()
+    }
+
+    fn item(url: &str) -> String {
+        serde_yaml::to_string(url).unwrap()
+    }
+
+    const BLOCK: &str = "\
+irc:
+  server: irc.example.org # the server
+feeds:
+  # the feeds
+  urls:
+    - https://a.example/feed
+    # b is noisy
+    - url: https://b.example/feed
+      maxnews: 2
+    - https://c.example/feed
+  maxnews: 10
+";
+
+    #[test]
+    fn remove_keeps_comments() {
+        let yaml = edit_sequence(BLOCK, "feeds", "urls", SeqEdit::Remove(1)).unwrap();
+        assert_eq!(
+            urls(&yaml),
+            serde_yaml::from_str::<serde_yaml::Value>(
+                "[https://a.example/feed, https://c.example/feed]"
+            )
+            .unwrap()
+        );
+        assert!(yaml.contains("# the server"));
+        assert!(yaml.contains("# the feeds"));
+        assert!(yaml.contains("maxnews: 10"));
+        assert!(!yaml.contains("maxnews: 2"));
+    }
+
+    #[test]
+    fn remove_bad_index() {
+        assert!(edit_sequence(BLOCK, "feeds", "urls", SeqEdit::Remove(3)).is_err());
+    }
+
+    #[test]
+    fn remove_last_feed() {
+        let yaml = "feeds:\n  # the feeds\n  urls:\n    - https://a.example/feed\n  maxnews: 10\n";
+        let yaml = edit_sequence(yaml, "feeds", "urls", SeqEdit::Remove(0)).unwrap();
+        assert_eq!(urls(&yaml), serde_yaml::Value::Sequence(vec![]));
+        assert!(yaml.contains("# the feeds"));
+        assert!(yaml.contains("maxnews: 10"));
+    }
+
+    #[test]
+    fn append() {
+        let yaml = edit_sequence(
+            BLOCK,
+            "feeds",
+            "urls",
+            SeqEdit::Append(vec![item("https://d.example/feed")]),
+        )
+        .unwrap();
+        let urls = urls(&yaml);
+        assert_eq!(urls.as_sequence().unwrap().len(), 4);
+        assert_eq!(urls[3], "https://d.example/feed");
+        assert_eq!(urls[1]["maxnews"], 2);
+        assert!(yaml.contains("# b is noisy"));
+        assert!(yaml.contains("maxnews: 10"));
+    }
+
+    #[test]
+    fn append_unindented_sequence() {
+        let yaml = "feeds:\n  urls:\n  - https://a.example/feed\n  maxnews: 10\n";
+        let yaml = edit_sequence(
+            yaml,
+            "feeds",
+            "urls",
+            SeqEdit::Append(vec![item("https://b.example/feed")]),
+        )
+        .unwrap();
+        assert_eq!(urls(&yaml)[1], "https://b.example/feed");
+        assert!(yaml.contains("maxnews: 10"));
+    }
+
+    #[test]
+    fn flow_sequence() {
+        let yaml = "feeds: # the feeds\n  urls: [https://a.example/feed, https://b.example/feed]\n";
+        let appended = edit_sequence(
+            yaml,
+            "feeds",
+            "urls",
+            SeqEdit::Append(vec![item("https://c.example/feed")]),
+        )
+        .unwrap();
+        assert_eq!(urls(&appended).as_sequence().unwrap().len(), 3);
+        assert!(appended.contains("# the feeds"));
+
+        let removed = edit_sequence(yaml, "feeds", "urls", SeqEdit::Remove(0)).unwrap();
+        assert_eq!(urls(&removed)[0], "https://b.example/feed");
+        assert_eq!(urls(&removed).as_sequence().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn flow_sequence_emptied() {
+        let yaml = "feeds:\n  urls: [https://a.example/feed]\n";
+        let yaml = edit_sequence(yaml, "feeds", "urls", SeqEdit::Remove(0)).unwrap();
+        assert_eq!(urls(&yaml), serde_yaml::Value::Sequence(vec![]));
+    }
+
+    #[test]
+    fn missing_key() {
+        let yaml = "feeds:\n  maxnews: 10\nirc:\n  server: irc.example.org\n";
+        let appended = edit_sequence(
+            yaml,
+            "feeds",
+            "urls",
+            SeqEdit::Append(vec![item("https://a.example/feed")]),
+        )
+        .unwrap();
+        assert_eq!(urls(&appended)[0], "https://a.example/feed");
+        assert!(appended.contains("maxnews: 10"));
+        assert!(appended.contains("server: irc.example.org"));
+
+        assert!(edit_sequence(yaml, "feeds", "urls", SeqEdit::Remove(0)).is_err());
+    }
+
+    #[test]
+    fn missing_section() {
+        let yaml = "irc:\n  server: irc.example.org\n";
+        assert!(edit_sequence(yaml, "feeds", "urls", SeqEdit::Append(vec![])).is_err());
+    }
+}